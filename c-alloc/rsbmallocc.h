@@ -3,6 +3,7 @@
 #include <stddef.h>
 #include <stdint.h>
 #include <stdlib.h>
+#include <stdio.h>
 
 void *malloc(size_t size);
 
@@ -14,6 +15,14 @@ void *realloc(void *ptr, size_t size);
 
 void *aligned_alloc(size_t alignment, size_t size);
 
+size_t malloc_usable_size(void *ptr);
+
+void malloc_stats(void);
+
+int malloc_trim(size_t pad);
+
+int malloc_info(int options, FILE *stream);
+
 void *valloc(size_t size);
 
 void *pvalloc(size_t size);
@@ -39,3 +48,9 @@ void *rsbpvalloc(size_t size);
 void *rsbmemalign(size_t alignment, size_t size);
 
 int rsbposix_memalign(void **memptr, size_t alignment, size_t size);
+
+void rsbmalloc_stats(void);
+
+int rsbmalloc_trim(size_t pad);
+
+int rsbmalloc_info(int options, FILE *stream);
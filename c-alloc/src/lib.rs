@@ -2,161 +2,316 @@
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cmp::max,
+    cmp::min,
     ffi::{c_int, c_void},
-    mem, ptr,
+    fmt, mem, ptr,
 };
-use rsbmalloc::{page_allocator::PAGE_SIZE, RSBMalloc};
+use rsbmalloc::{page_allocator::PAGE_SIZE, RSBMalloc, StatsFormat};
 
 static ALLOCATOR: RSBMalloc = RSBMalloc::new();
 
-fn create_alloc_layout(size: usize) -> Layout {
-    Layout::from_size_align(
-        size + mem::size_of::<usize>(),
-        max(mem::size_of::<usize>(), get_align(size)),
-    )
-    .unwrap()
+/// Every block starts with a header right before the returned pointer: the
+/// alignment it was allocated with (0 for plain `malloc` blocks) followed by
+/// the requested size. 16 bytes keeps `malloc` results suitably aligned for
+/// any C type.
+const HEADER_SIZE: usize = 2 * mem::size_of::<usize>();
+
+fn create_alloc_layout(size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.checked_add(HEADER_SIZE)?, HEADER_SIZE).ok()
 }
 
-fn create_calloc_layout(count: usize, size: usize) -> Layout {
-    Layout::from_size_align(
-        size * count + mem::size_of::<usize>(),
-        max(mem::size_of::<usize>(), get_align(size)),
-    )
-    .unwrap()
+fn create_aligned_layout(alignment: usize, size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.checked_add(alignment)?, alignment).ok()
 }
 
-fn get_align(size: usize) -> usize {
-    2_usize.pow(size.ilog2())
+/// Writes the header for a block starting at `base` and returns the pointer
+/// handed to C
+unsafe fn finish_alloc(base: *mut u8, offset: usize, alignment: usize, size: usize) -> *mut c_void {
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    let user = base.add(offset) as *mut usize;
+    user.sub(2).write(alignment);
+    user.sub(1).write(size);
+    user as *mut c_void
+}
+
+/// Recovers the block start and layout from a pointer handed to C
+unsafe fn block_of(ptr: *mut c_void) -> (*mut u8, Layout, usize) {
+    let user = ptr as *mut usize;
+    let alignment = user.sub(2).read();
+    let size = user.sub(1).read();
+    if alignment == 0 {
+        let layout = create_alloc_layout(size).unwrap();
+        ((ptr as *mut u8).sub(HEADER_SIZE), layout, size)
+    } else {
+        let layout = create_aligned_layout(alignment, size).unwrap();
+        ((ptr as *mut u8).sub(alignment), layout, size)
+    }
 }
 
+/// # Safety
+/// Same contract as C `malloc`
 #[no_mangle]
 pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
-    if size == 0 {
-        ptr::null_mut()
-    } else {
-        let ptr = ALLOCATOR.alloc(create_alloc_layout(size)) as *mut usize;
-        ptr.write(size);
-        ptr.add(1) as *mut c_void
+    match create_alloc_layout(size) {
+        Some(layout) => finish_alloc(ALLOCATOR.alloc(layout), HEADER_SIZE, 0, size),
+        None => ptr::null_mut(),
     }
 }
 
+/// # Safety
+/// Same contract as C `free`
 #[no_mangle]
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
     if ptr.is_null() {
         return;
     }
-    let ptr = (ptr as *mut usize).sub(1);
-    let size = ptr.read();
-    if size == 0 {
-        return;
-    } else {
-        ALLOCATOR.dealloc(ptr as *mut u8, create_alloc_layout(size));
-    }
+    let (base, layout, _) = block_of(ptr);
+    ALLOCATOR.dealloc(base, layout);
 }
 
+/// # Safety
+/// Same contract as C `calloc`
 #[no_mangle]
 pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
-    let ptr = ALLOCATOR.alloc_zeroed(create_calloc_layout(count, size)) as *mut usize;
-    ptr.write(count * size);
-    ptr.add(1) as *mut c_void
+    let size = match count.checked_mul(size) {
+        Some(size) => size,
+        None => return ptr::null_mut(),
+    };
+    match create_alloc_layout(size) {
+        Some(layout) => finish_alloc(ALLOCATOR.alloc_zeroed(layout), HEADER_SIZE, 0, size),
+        None => ptr::null_mut(),
+    }
 }
 
+/// # Safety
+/// Same contract as C `realloc`
 #[no_mangle]
 pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
     if ptr.is_null() {
-        malloc(size)
+        return malloc(size);
+    }
+    let (base, layout, _) = block_of(ptr);
+    let new_layout = match create_alloc_layout(size) {
+        Some(layout) => layout,
+        None => return ptr::null_mut(),
+    };
+    let usable = usable_size(ptr);
+    if size <= usable && (ptr as *mut usize).sub(2).read() == 0 {
+        let new_base = ALLOCATOR.realloc(base, layout, new_layout.size());
+        finish_alloc(new_base, HEADER_SIZE, 0, size)
     } else {
-        let full_ptr = (ptr as *mut usize).sub(1);
-        let old_size = full_ptr.read();
-        let layout = create_alloc_layout(old_size);
-        let new_ptr = ALLOCATOR.realloc(full_ptr as *mut u8, layout, size + mem::size_of::<usize>())
-            as *mut usize;
-        new_ptr.write(size);
-        new_ptr.add(1) as *mut c_void
+        // Moved here rather than with `GlobalAlloc::realloc`, which would
+        // copy only the size asked for, losing whatever C wrote past it
+        // after `malloc_usable_size`, and would keep an aligned block's
+        // alignment while the new header describes a plain block
+        let new_ptr = malloc(size);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, min(usable, size));
+            free(ptr);
+        }
+        new_ptr
+    }
+}
+
+/// Bytes usable at `ptr`: the rest of its slot or mapping past the header
+unsafe fn usable_size(ptr: *mut c_void) -> usize {
+    let (base, _, size) = block_of(ptr);
+    match ALLOCATOR.usable_size(base) {
+        Some(usable) => usable - (ptr as usize - base as usize),
+        None => size,
     }
 }
 
+/// # Safety
+/// Same contract as C `aligned_alloc`
 #[no_mangle]
 pub unsafe extern "C" fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
-    let usize_size = max(mem::size_of::<usize>(), alignment);
-    let ptr = ALLOCATOR
-        .alloc(Layout::from_size_align_unchecked(
-            size + usize_size,
-            alignment,
-        ))
-        .add(usize_size) as *mut usize;
-    ptr.write(size);
-    ptr.add(1) as *mut c_void
+    if !alignment.is_power_of_two() {
+        return ptr::null_mut();
+    }
+    if alignment <= HEADER_SIZE {
+        return malloc(size);
+    }
+    match create_aligned_layout(alignment, size) {
+        Some(layout) => finish_alloc(ALLOCATOR.alloc(layout), alignment, alignment, size),
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// Same contract as glibc `malloc_usable_size`
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
+    if ptr.is_null() {
+        0
+    } else {
+        usable_size(ptr)
+    }
+}
+
+/// Formats straight into a file descriptor, since `malloc_stats` can't
+/// allocate a buffer
+struct FdWriter(c_int);
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written = unsafe { libc::write(self.0, bytes.as_ptr() as _, bytes.len() as _) };
+            if written <= 0 {
+                return Err(fmt::Error);
+            }
+            bytes = &bytes[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+struct FileWriter(*mut libc::FILE);
+
+impl fmt::Write for FileWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let written = unsafe { libc::fwrite(s.as_ptr() as _, 1, s.len(), self.0) };
+        if written == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+/// Prints usage per size class and per thread cache to stderr. Takes no
+/// locks and writes with `write(2)`, so it may be called from a signal
+/// handler.
+#[no_mangle]
+pub extern "C" fn malloc_stats() {
+    let _ = ALLOCATOR.dump_stats(&mut FdWriter(2), StatsFormat::Table);
+}
+
+/// Returns free memory to the OS, leaving up to `pad` bytes of it in place.
+/// Returns 1 if anything was released, 0 otherwise.
+#[no_mangle]
+pub extern "C" fn malloc_trim(pad: usize) -> c_int {
+    (ALLOCATOR.trim(pad) > 0) as c_int
+}
+
+/// Writes the same statistics as `malloc_stats` to `stream`, as JSON rather
+/// than glibc's XML. `options` must be 0.
+///
+/// # Safety
+/// `stream` must be a valid, writable `FILE`
+#[no_mangle]
+pub unsafe extern "C" fn malloc_info(options: c_int, stream: *mut libc::FILE) -> c_int {
+    if options != 0 || stream.is_null() {
+        return -1;
+    }
+    match ALLOCATOR.dump_stats(&mut FileWriter(stream), StatsFormat::Json) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
 }
 
+/// # Safety
+/// Same contract as C `valloc`
 #[no_mangle]
 pub unsafe extern "C" fn valloc(size: usize) -> *mut c_void {
     aligned_alloc(*PAGE_SIZE, size)
 }
+
+/// # Safety
+/// Same contract as C `pvalloc`
 #[no_mangle]
 pub unsafe extern "C" fn pvalloc(size: usize) -> *mut c_void {
     aligned_alloc(*PAGE_SIZE, size)
 }
 
+/// # Safety
+/// Same contract as C `memalign`
 #[no_mangle]
 pub unsafe extern "C" fn memalign(alignment: usize, size: usize) -> *mut c_void {
     aligned_alloc(alignment, size)
 }
 
+/// # Safety
+/// Same contract as C `posix_memalign`
 #[no_mangle]
 pub unsafe extern "C" fn posix_memalign(
     memptr: *mut *mut c_void,
     alignment: usize,
     size: usize,
 ) -> c_int {
-    *memptr = aligned_alloc(alignment, size);
-    if (*memptr).is_null() {
-        return 1;
+    if alignment < mem::size_of::<usize>() || !alignment.is_power_of_two() {
+        return libc::EINVAL;
+    }
+    let ptr = aligned_alloc(alignment, size);
+    if ptr.is_null() {
+        libc::ENOMEM
     } else {
-        return 0;
+        *memptr = ptr;
+        0
     }
 }
 
+/// # Safety
+/// Same contract as C `malloc`
 #[no_mangle]
 pub unsafe extern "C" fn rsbmalloc(size: usize) -> *mut c_void {
     malloc(size)
 }
 
+/// # Safety
+/// Same contract as C `free`
 #[no_mangle]
 pub unsafe extern "C" fn rsbfree(ptr: *mut c_void) {
     free(ptr)
 }
 
+/// # Safety
+/// Same contract as C `calloc`
 #[no_mangle]
 pub unsafe extern "C" fn rsbcalloc(count: usize, size: usize) -> *mut c_void {
     calloc(count, size)
 }
 
+/// # Safety
+/// Same contract as C `realloc`
 #[no_mangle]
 pub unsafe extern "C" fn rsbrealloc(ptr: *mut c_void, size: usize) -> *mut c_void {
     realloc(ptr, size)
 }
 
+/// # Safety
+/// Same contract as C `aligned_alloc`
 #[no_mangle]
 pub unsafe extern "C" fn rsbaligned_alloc(alignment: usize, size: usize) -> *mut c_void {
     aligned_alloc(alignment, size)
 }
 
+/// # Safety
+/// Same contract as C `valloc`
 #[no_mangle]
 pub unsafe extern "C" fn rsbvalloc(size: usize) -> *mut c_void {
     aligned_alloc(*PAGE_SIZE, size)
 }
+
+/// # Safety
+/// Same contract as C `pvalloc`
 #[no_mangle]
 pub unsafe extern "C" fn rsbpvalloc(size: usize) -> *mut c_void {
     aligned_alloc(*PAGE_SIZE, size)
 }
 
+/// # Safety
+/// Same contract as C `memalign`
 #[no_mangle]
 pub unsafe extern "C" fn rsbmemalign(alignment: usize, size: usize) -> *mut c_void {
     aligned_alloc(alignment, size)
 }
 
+/// # Safety
+/// Same contract as C `posix_memalign`
 #[no_mangle]
 pub unsafe extern "C" fn rsbposix_memalign(
     memptr: *mut *mut c_void,
@@ -165,3 +320,199 @@ pub unsafe extern "C" fn rsbposix_memalign(
 ) -> c_int {
     posix_memalign(memptr, alignment, size)
 }
+
+#[no_mangle]
+pub extern "C" fn rsbmalloc_stats() {
+    malloc_stats()
+}
+
+#[no_mangle]
+pub extern "C" fn rsbmalloc_trim(pad: usize) -> c_int {
+    malloc_trim(pad)
+}
+
+/// # Safety
+/// Same contract as `malloc_info`
+#[no_mangle]
+pub unsafe extern "C" fn rsbmalloc_info(options: c_int, stream: *mut libc::FILE) -> c_int {
+    malloc_info(options, stream)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::{slice, thread, vec::Vec};
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// One call into the shim. Indices pick a live block, modulo how many
+    /// there are.
+    #[derive(Clone, Debug)]
+    enum Op {
+        Malloc(usize),
+        Calloc(usize, usize),
+        Memalign(usize, usize),
+        Realloc(usize, usize),
+        Free(usize),
+    }
+
+    /// Sizes spread evenly over every size class and well into large
+    /// allocations, including 0
+    fn any_size() -> impl Strategy<Value = usize> {
+        (0..19u32, any::<usize>()).prop_map(|(bits, n)| n % (1 << bits))
+    }
+
+    fn any_op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            2 => any_size().prop_map(Op::Malloc),
+            1 => (0..64usize, any_size()).prop_map(|(count, size)| Op::Calloc(count, size / 64)),
+            1 => (3..13u32, any_size()).prop_map(|(shift, size)| Op::Memalign(1 << shift, size)),
+            1 => (any::<usize>(), any_size()).prop_map(|(i, size)| Op::Realloc(i, size)),
+            1 => any::<usize>().prop_map(Op::Free),
+        ]
+    }
+
+    /// A live block, filled with a byte of its own up to its usable size
+    struct Filled {
+        ptr: *mut c_void,
+        size: usize,
+        byte: u8,
+    }
+
+    unsafe impl Send for Filled {}
+
+    impl Filled {
+        unsafe fn check(&self, len: usize) {
+            let contents = slice::from_raw_parts(self.ptr as *const u8, len);
+            assert!(
+                contents.iter().all(|&byte| byte == self.byte),
+                "block at {:p} was corrupted",
+                self.ptr
+            );
+        }
+    }
+
+    /// Runs `ops` through the prefixed functions, filling every block with a
+    /// byte of its own and checking it's intact before the block is resized
+    /// or freed. Returns the blocks still live, unchecked.
+    unsafe fn run_ops(ops: &[Op], first_byte: u8) -> Vec<Filled> {
+        let mut live: Vec<Filled> = Vec::new();
+        let mut byte = first_byte;
+        for op in ops {
+            let (ptr, size) = match *op {
+                Op::Malloc(size) => (rsbmalloc(size), size),
+                Op::Calloc(count, size) => {
+                    let ptr = rsbcalloc(count, size);
+                    assert!(!ptr.is_null());
+                    assert!(slice::from_raw_parts(ptr as *const u8, count * size)
+                        .iter()
+                        .all(|&byte| byte == 0));
+                    (ptr, count * size)
+                }
+                Op::Memalign(alignment, size) => {
+                    let mut ptr = ptr::null_mut();
+                    assert_eq!(rsbposix_memalign(&mut ptr, alignment, size), 0);
+                    assert_eq!(ptr as usize % alignment, 0);
+                    (ptr, size)
+                }
+                Op::Realloc(i, size) if !live.is_empty() => {
+                    let len = live.len();
+                    let block = &mut live[i % len];
+                    block.check(block.size);
+                    let ptr = rsbrealloc(block.ptr, size);
+                    assert!(!ptr.is_null());
+                    let kept = min(block.size, size);
+                    block.ptr = ptr;
+                    block.size = malloc_usable_size(ptr);
+                    block.check(kept);
+                    assert!(block.size >= size);
+                    (ptr as *mut u8).write_bytes(block.byte, block.size);
+                    continue;
+                }
+                Op::Free(i) if !live.is_empty() => {
+                    let block = live.swap_remove(i % live.len());
+                    block.check(block.size);
+                    rsbfree(block.ptr);
+                    continue;
+                }
+                _ => continue,
+            };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % HEADER_SIZE, 0);
+            let usable = malloc_usable_size(ptr);
+            assert!(usable >= size);
+            byte = byte.wrapping_add(1);
+            (ptr as *mut u8).write_bytes(byte, usable);
+            live.push(Filled {
+                ptr,
+                size: usable,
+                byte,
+            });
+        }
+        live
+    }
+
+    proptest! {
+        // Set PROPTEST_CASES for a longer soak
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn random_ops(ops in prop::collection::vec(any_op(), 1..300)) {
+            unsafe {
+                for block in run_ops(&ops, 0) {
+                    block.check(block.size);
+                    rsbfree(block.ptr);
+                }
+            }
+        }
+
+        #[test]
+        fn random_ops_threaded(
+            threads in prop::collection::vec(prop::collection::vec(any_op(), 1..200), 2..8)
+        ) {
+            let handles: Vec<_> = threads
+                .into_iter()
+                .enumerate()
+                .map(|(i, ops)| thread::spawn(move || unsafe { run_ops(&ops, (i * 32) as u8) }))
+                .collect();
+            // Whatever's left is freed here, away from the thread that
+            // allocated it
+            for handle in handles {
+                for block in handle.join().unwrap() {
+                    unsafe {
+                        block.check(block.size);
+                        rsbfree(block.ptr);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn usable_size() {
+        unsafe {
+            // 20 bytes and the header fit the 64-byte class
+            let small = rsbmalloc(20);
+            assert_eq!(malloc_usable_size(small), 64 - HEADER_SIZE);
+            // The header of an aligned block takes a whole alignment
+            let aligned = rsbaligned_alloc(64, 10);
+            assert_eq!(malloc_usable_size(aligned), 128 - 64);
+            let large = rsbmalloc(100_000);
+            let usable = malloc_usable_size(large);
+            assert!(usable >= 100_000);
+            assert_eq!((usable + HEADER_SIZE) % *PAGE_SIZE, 0);
+
+            // Bytes past the requested size survive a move
+            (small as *mut u8).write_bytes(7, 64 - HEADER_SIZE);
+            let moved = rsbrealloc(small, 1000);
+            assert!(slice::from_raw_parts(moved as *const u8, 64 - HEADER_SIZE)
+                .iter()
+                .all(|&byte| byte == 7));
+            for ptr in [moved, aligned, large] {
+                rsbfree(ptr);
+            }
+        }
+    }
+}
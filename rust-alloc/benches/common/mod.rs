@@ -0,0 +1,206 @@
+//! Allocators to compare and the workloads run against each of them. Every
+//! workload calls the allocator directly, so several can be compared in one
+//! process while the benchmark itself runs on the system allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+use rsbmalloc::RSBMalloc;
+
+pub type Allocator = &'static (dyn GlobalAlloc + Sync);
+
+static RSBMALLOC: RSBMalloc = RSBMalloc::new();
+static SYSTEM: System = System;
+static MIMALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
+#[cfg(not(target_env = "msvc"))]
+static JEMALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Every allocator under comparison, `rsbmalloc` first
+pub fn allocators() -> Vec<(&'static str, Allocator)> {
+    vec![
+        ("rsbmalloc", &RSBMALLOC as Allocator),
+        ("system", &SYSTEM),
+        #[cfg(not(target_env = "msvc"))]
+        ("jemalloc", &JEMALLOC),
+        ("mimalloc", &MIMALLOC),
+    ]
+}
+
+/// Xorshift, so every allocator sees the same sizes in the same order
+/// without pulling in a crate
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    /// A size from `min` to `max` inclusive
+    pub fn size(&mut self, min: usize, max: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        min + (self.0 % (max - min + 1) as u64) as usize
+    }
+}
+
+/// A live allocation, which may be freed on another thread
+pub struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+unsafe impl Send for Block {}
+
+impl Block {
+    /// Allocates `size` bytes and touches them, like a real caller would
+    pub fn new(allocator: Allocator, size: usize) -> Self {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null(), "out of memory");
+        unsafe { ptr.write_bytes(0xa5, size) };
+        Self { ptr, layout }
+    }
+
+    pub fn free(self, allocator: Allocator) {
+        unsafe { allocator.dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Keeps a working set of `live` blocks of 8 to 1024 bytes, replacing a
+/// random one `ops` times
+pub fn churn(allocator: Allocator, live: usize, ops: usize) {
+    let mut rng = Rng::new(1);
+    let mut blocks: Vec<Block> = (0..live)
+        .map(|_| Block::new(allocator, rng.size(8, 1024)))
+        .collect();
+    for _ in 0..ops {
+        let i = rng.size(0, live - 1);
+        let block = Block::new(allocator, rng.size(8, 1024));
+        std::mem::replace(&mut blocks[i], block).free(allocator);
+    }
+    for block in blocks {
+        block.free(allocator);
+    }
+}
+
+/// One thread allocates `blocks` blocks and another frees them, so every
+/// free is remote
+pub fn producer_consumer(allocator: Allocator, blocks: usize) {
+    let (sender, receiver) = mpsc::sync_channel::<Block>(1024);
+    let consumer = thread::spawn(move || {
+        for block in receiver {
+            block.free(allocator);
+        }
+    });
+    let mut rng = Rng::new(2);
+    for _ in 0..blocks {
+        sender
+            .send(Block::new(allocator, rng.size(8, 512)))
+            .unwrap();
+    }
+    drop(sender);
+    consumer.join().unwrap();
+}
+
+/// Larson: `threads` threads each replace random blocks of their own working
+/// set of 10 to 500 byte blocks. Each generation hands its working sets to
+/// fresh threads, which free what the last generation allocated.
+pub fn larson(allocator: Allocator, threads: usize, generations: usize, ops: usize) {
+    const LIVE: usize = 1000;
+    let mut sets: Vec<Vec<Block>> = (0..threads)
+        .map(|t| {
+            let mut rng = Rng::new(t as u64 + 3);
+            (0..LIVE)
+                .map(|_| Block::new(allocator, rng.size(10, 500)))
+                .collect()
+        })
+        .collect();
+    for generation in 0..generations {
+        sets = sets
+            .into_iter()
+            .enumerate()
+            .map(|(t, mut blocks)| {
+                thread::spawn(move || {
+                    let mut rng = Rng::new((generation * threads + t) as u64 + 3);
+                    for _ in 0..ops {
+                        let i = rng.size(0, LIVE - 1);
+                        let block = Block::new(allocator, rng.size(10, 500));
+                        std::mem::replace(&mut blocks[i], block).free(allocator);
+                    }
+                    blocks
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+    }
+    for block in sets.into_iter().flatten() {
+        block.free(allocator);
+    }
+}
+
+/// xmalloc-test: `threads` producers allocate batches of 64 blocks and
+/// `threads` consumers free whichever batches they find first, so blocks
+/// move between every pair of threads
+pub fn xmalloc(allocator: Allocator, threads: usize, batches: usize) {
+    let queue: Arc<Mutex<Vec<Vec<Block>>>> = Arc::new(Mutex::new(Vec::new()));
+    let freed = Arc::new(AtomicUsize::new(0));
+    let total = threads * batches;
+    let producers = (0..threads).map(|t| {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            let mut rng = Rng::new(t as u64 + 5);
+            for _ in 0..batches {
+                let batch = (0..64)
+                    .map(|_| Block::new(allocator, rng.size(8, 256)))
+                    .collect();
+                queue.lock().unwrap().push(batch);
+            }
+        })
+    });
+    let consumers = (0..threads).map(|_| {
+        let queue = queue.clone();
+        let freed = freed.clone();
+        thread::spawn(move || {
+            while freed.load(Ordering::Relaxed) < total {
+                let batch = queue.lock().unwrap().pop();
+                match batch {
+                    Some(batch) => {
+                        for block in batch {
+                            block.free(allocator);
+                        }
+                        freed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+        })
+    });
+    let handles: Vec<_> = producers.chain(consumers).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// Grows a buffer `step` bytes at a time up to `max` bytes, the way a string
+/// being appended to does
+pub fn realloc_growth(allocator: Allocator, step: usize, max: usize) {
+    let mut layout = Layout::from_size_align(step, 8).unwrap();
+    let mut ptr = unsafe { allocator.alloc(layout) };
+    while layout.size() < max {
+        let new_size = layout.size() + step;
+        ptr = unsafe { allocator.realloc(ptr, layout, new_size) };
+        assert!(!ptr.is_null(), "out of memory");
+        unsafe { ptr.add(new_size - 1).write(1) };
+        layout = Layout::from_size_align(new_size, 8).unwrap();
+    }
+    unsafe { allocator.dealloc(ptr, layout) };
+}
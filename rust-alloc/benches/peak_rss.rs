@@ -0,0 +1,85 @@
+//! Peak resident memory of each workload in `common` under every allocator.
+//! Each run gets a process of its own, so peaks don't carry over. Run with
+//! `cargo bench --bench peak_rss`. Only Linux reports a peak.
+
+// Benchmarks are only run on current toolchains
+#![allow(clippy::incompatible_msrv)]
+
+mod common;
+
+use std::{env, fs, process::Command};
+
+use common::Allocator;
+
+const THREADS: usize = 4;
+/// Set in the child process to the workload and allocator to run
+const CHILD_VAR: &str = "RSBMALLOC_PEAK_RSS";
+
+type Workload = (&'static str, fn(Allocator));
+
+const WORKLOADS: [Workload; 5] = [
+    ("churn", |allocator| {
+        common::churn(allocator, 100_000, 1_000_000)
+    }),
+    ("producer_consumer", |allocator| {
+        common::producer_consumer(allocator, 1_000_000)
+    }),
+    ("larson", |allocator| {
+        common::larson(allocator, THREADS, 8, 100_000)
+    }),
+    ("xmalloc", |allocator| {
+        common::xmalloc(allocator, THREADS, 5000)
+    }),
+    ("realloc_growth", |allocator| {
+        common::realloc_growth(allocator, 24, 16 << 20)
+    }),
+];
+
+/// `VmHWM` from `/proc/self/status`, in KiB
+fn peak_rss_kib() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn run_child(spec: &str) {
+    let (workload, allocator) = spec.split_once('/').unwrap();
+    let (_, run) = WORKLOADS
+        .iter()
+        .find(|(name, _)| *name == workload)
+        .unwrap();
+    let (_, allocator) = common::allocators()
+        .into_iter()
+        .find(|(name, _)| *name == allocator)
+        .unwrap();
+    run(allocator);
+    match peak_rss_kib() {
+        Some(kib) => println!("{kib}"),
+        None => println!("n/a"),
+    }
+}
+
+fn main() {
+    if let Ok(spec) = env::var(CHILD_VAR) {
+        return run_child(&spec);
+    }
+    let exe = env::current_exe().unwrap();
+    let allocators = common::allocators();
+    print!("{:>18}", "peak RSS (KiB)");
+    for (name, _) in &allocators {
+        print!("{name:>12}");
+    }
+    println!();
+    for (workload, _) in WORKLOADS {
+        print!("{workload:>18}");
+        for (allocator, _) in &allocators {
+            let output = Command::new(&exe)
+                .env(CHILD_VAR, format!("{workload}/{allocator}"))
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{workload} failed on {allocator}");
+            print!("{:>12}", String::from_utf8_lossy(&output.stdout).trim());
+        }
+        println!();
+    }
+}
@@ -0,0 +1,99 @@
+//! Times getting from a size to its size class, with the `match` ladder bins
+//! used to be picked with as a baseline, and an alloc/dealloc pair in every
+//! size class. Run with `cargo bench --bench size_classes`.
+
+// Benchmarks are only run on current toolchains
+#![allow(clippy::incompatible_msrv)]
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    hint::black_box,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rsbmalloc::RSBMalloc;
+
+static ALLOCATOR: RSBMalloc = RSBMalloc::new();
+
+/// The lookup in `size_class`, which isn't public
+fn leading_zeros(size: usize) -> Option<usize> {
+    if size > 0x10000 {
+        return None;
+    }
+    Some((usize::BITS - (size.max(4) - 1).leading_zeros()) as usize - 2)
+}
+
+/// The ladder `alloc_inner` and `dealloc_inner` matched sizes against before
+#[allow(clippy::match_overlapping_arm)]
+fn match_ladder(size: usize) -> Option<usize> {
+    Some(match size {
+        ..=4 => 0,
+        ..=8 => 1,
+        ..=16 => 2,
+        ..=32 => 3,
+        ..=64 => 4,
+        ..=128 => 5,
+        ..=256 => 6,
+        ..=512 => 7,
+        ..=1024 => 8,
+        ..=2048 => 9,
+        ..=4096 => 10,
+        ..=8192 => 11,
+        ..=16384 => 12,
+        ..=0x8000 => 13,
+        ..=0x10000 => 14,
+        _ => return None,
+    })
+}
+
+fn lookup(c: &mut Criterion) {
+    // Cycling through every class keeps the branch predictor from learning
+    // a single size
+    let sizes: Vec<usize> = (0..15).map(|class| (4 << class) - 1).collect();
+    assert!(sizes
+        .iter()
+        .all(|&size| leading_zeros(size) == match_ladder(size)));
+    let mut group = c.benchmark_group("lookup");
+    for (name, lookup) in [
+        ("match", match_ladder as fn(usize) -> Option<usize>),
+        ("leading_zeros", leading_zeros),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for &size in &sizes {
+                    black_box(lookup(black_box(size)));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn alloc_dealloc(c: &mut Criterion) {
+    let layouts: Vec<Layout> = (0..15)
+        .map(|class| Layout::from_size_align((4 << class) - 1, 1).unwrap())
+        .collect();
+    let mut group = c.benchmark_group("alloc_dealloc");
+    for layout in &layouts {
+        group.bench_function(format!("{} bytes", layout.size()), |b| {
+            b.iter(|| unsafe {
+                let ptr = ALLOCATOR.alloc(black_box(*layout));
+                ALLOCATOR.dealloc(black_box(ptr), *layout);
+            })
+        });
+    }
+    group.bench_function("mixed", |b| {
+        b.iter(|| {
+            for &layout in &layouts {
+                unsafe {
+                    let ptr = ALLOCATOR.alloc(black_box(layout));
+                    ALLOCATOR.dealloc(black_box(ptr), layout);
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, lookup, alloc_dealloc);
+criterion_main!(benches);
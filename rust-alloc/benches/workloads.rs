@@ -0,0 +1,46 @@
+//! Times each workload in `common` against every allocator, side by side.
+//! Run with `cargo bench --bench workloads`, or pick workloads by name, e.g.
+//! `cargo bench --bench workloads -- larson`.
+
+// Benchmarks are only run on current toolchains
+#![allow(clippy::incompatible_msrv)]
+
+mod common;
+
+use std::time::Duration;
+
+use common::Allocator;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const THREADS: usize = 4;
+
+fn compare(c: &mut Criterion, name: &str, workload: impl Fn(Allocator)) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(20);
+    group.measurement_time(Duration::from_secs(5));
+    for (allocator_name, allocator) in common::allocators() {
+        group.bench_function(allocator_name, |b| b.iter(|| workload(allocator)));
+    }
+    group.finish();
+}
+
+fn workloads(c: &mut Criterion) {
+    compare(c, "churn", |allocator| {
+        common::churn(allocator, 1000, 100_000)
+    });
+    compare(c, "producer_consumer", |allocator| {
+        common::producer_consumer(allocator, 100_000)
+    });
+    compare(c, "larson", |allocator| {
+        common::larson(allocator, THREADS, 4, 20_000)
+    });
+    compare(c, "xmalloc", |allocator| {
+        common::xmalloc(allocator, THREADS, 500)
+    });
+    compare(c, "realloc_growth", |allocator| {
+        common::realloc_growth(allocator, 24, 1 << 20)
+    });
+}
+
+criterion_group!(benches, workloads);
+criterion_main!(benches);
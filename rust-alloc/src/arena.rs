@@ -0,0 +1,233 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cmp::max,
+    fmt, ptr,
+};
+
+use spin::Mutex;
+
+use crate::{
+    chunks::ChunkList,
+    limit,
+    page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE},
+    RSB_CHUNK_SIZE,
+};
+
+/// Bump allocator over a chain of chunks from `PAGE_ALLOCATOR`.
+///
+/// Allocations are carved one after the other and never freed one by one,
+/// except that freeing or resizing the most recent one moves the cursor.
+/// Memory comes back all at once with `reset`, by rolling back to a
+/// `Checkpoint`, or when the arena is dropped.
+///
+/// Chunks are 64 KiB unless a single allocation needs more, in which case it
+/// gets a mapping of its own.
+pub struct Arena {
+    state: Mutex<ArenaState>,
+}
+
+struct ArenaState {
+    /// Every mapping, the one being carved last
+    chunks: ChunkList,
+    cursor: usize,
+    end: usize,
+}
+
+/// Position in an `Arena` to roll back to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    chunks: usize,
+    chunk: usize,
+    generation: usize,
+    cursor: usize,
+}
+
+impl Arena {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(ArenaState {
+                chunks: ChunkList::new(),
+                cursor: 0,
+                end: 0,
+            }),
+        }
+    }
+
+    /// Bytes mapped for the arena's chunks
+    pub fn mapped_bytes(&self) -> usize {
+        let state = self.state.lock();
+        state.chunks.as_slice().iter().map(|chunk| chunk.len).sum()
+    }
+
+    /// Frees everything allocated so far, keeping the first chunk mapped
+    pub fn reset(&mut self) {
+        self.state.get_mut().truncate(1);
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        let state = self.state.lock();
+        let (chunk, generation) = state
+            .chunks
+            .as_slice()
+            .last()
+            .map_or((0, 0), |chunk| (chunk.ptr as usize, chunk.generation));
+        Checkpoint {
+            chunks: state.chunks.len(),
+            chunk,
+            generation,
+            cursor: state.cursor,
+        }
+    }
+
+    /// Frees everything allocated since `checkpoint` was taken, unmapping the
+    /// chunks started since. Returns false, leaving the arena alone, if
+    /// `checkpoint` was already rolled back past, even if a chunk mapped
+    /// since landed at the same address.
+    ///
+    /// # Safety
+    /// `checkpoint` must come from this arena, and nothing allocated after it
+    /// may be used again.
+    pub unsafe fn rollback(&self, checkpoint: Checkpoint) -> bool {
+        let mut state = self.state.lock();
+        let current = match checkpoint.chunks {
+            0 => Some((0, 0)),
+            chunks => state
+                .chunks
+                .as_slice()
+                .get(chunks - 1)
+                .map(|chunk| (chunk.ptr as usize, chunk.generation)),
+        };
+        if current != Some((checkpoint.chunk, checkpoint.generation)) {
+            return false;
+        }
+        state.truncate(checkpoint.chunks);
+        state.cursor = checkpoint.cursor;
+        true
+    }
+
+    /// Runs `f`, then frees everything it allocated. Nothing borrowed from
+    /// the arena can escape `f`, which makes this the safe way to roll back.
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Self) -> R) -> R {
+        let checkpoint = self.checkpoint();
+        let result = f(self);
+        unsafe { self.rollback(checkpoint) };
+        result
+    }
+}
+
+impl ArenaState {
+    fn bump(&mut self, layout: Layout) -> *mut u8 {
+        let start = (self.cursor + layout.align() - 1) & !(layout.align() - 1);
+        if self.end != 0 {
+            if let Some(end) = start
+                .checked_add(layout.size())
+                .filter(|&end| end <= self.end)
+            {
+                self.cursor = end;
+                return start as *mut u8;
+            }
+        }
+        let chunk_layout = match Layout::from_size_align(
+            max(limit::mapped_size(layout.size()), RSB_CHUNK_SIZE),
+            max(layout.align(), *PAGE_SIZE),
+        ) {
+            Ok(chunk_layout) => chunk_layout.pad_to_align(),
+            Err(_) => return ptr::null_mut(),
+        };
+        unsafe {
+            let chunk = PAGE_ALLOCATOR.alloc(chunk_layout);
+            if chunk.is_null() {
+                return ptr::null_mut();
+            }
+            if !self.chunks.push(chunk, chunk_layout) {
+                PAGE_ALLOCATOR.dealloc(chunk, chunk_layout);
+                return ptr::null_mut();
+            }
+            self.cursor = chunk as usize + layout.size();
+            self.end = chunk as usize + chunk_layout.size();
+            chunk
+        }
+    }
+
+    /// Unmaps all but the first `keep` chunks and carves the last one kept
+    /// from its start
+    fn truncate(&mut self, keep: usize) {
+        for chunk in self.chunks.as_slice().iter().skip(keep) {
+            unsafe { PAGE_ALLOCATOR.dealloc(chunk.ptr, chunk.layout()) };
+        }
+        self.chunks.truncate(keep);
+        match self.chunks.as_slice().last() {
+            Some(chunk) => {
+                self.cursor = chunk.ptr as usize;
+                self.end = chunk.ptr as usize + chunk.len;
+            }
+            None => {
+                self.cursor = 0;
+                self.end = 0;
+            }
+        }
+    }
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        self.state.get_mut().truncate(0);
+    }
+}
+
+impl fmt::Debug for Arena {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Arena")
+            .field("mapped_bytes", &self.mapped_bytes())
+            .finish()
+    }
+}
+
+unsafe impl GlobalAlloc for Arena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.state.lock().bump(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut state = self.state.lock();
+        if ptr as usize + layout.size() == state.cursor {
+            state.cursor = ptr as usize;
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut state = self.state.lock();
+        let end = ptr as usize + layout.size();
+        if end == state.cursor && ptr as usize + new_size <= state.end {
+            state.cursor = ptr as usize + new_size;
+            return ptr;
+        }
+        if new_size <= layout.size() {
+            return ptr;
+        }
+        let new_ptr = state.bump(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size());
+        }
+        new_ptr
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl core::alloc::Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        let ptr = unsafe { self.alloc(layout) };
+        ptr::NonNull::new(ptr::slice_from_raw_parts_mut(ptr, layout.size()))
+            .ok_or(core::alloc::AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: ptr::NonNull<u8>, layout: Layout) {
+        self.dealloc(ptr.as_ptr(), layout)
+    }
+}
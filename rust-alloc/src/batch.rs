@@ -0,0 +1,116 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+#[cfg(feature = "std")]
+use crate::tags::Tag;
+use crate::{page_allocator::PageSource, RSBMalloc, MAX_ALIGN};
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Allocates up to `out.len()` blocks of `layout` into `out`, returning
+    /// how many were allocated. Fewer than asked means the rest failed, as a
+    /// null from `alloc` would, and the entries past them are set to null.
+    ///
+    /// Blocks that fit a bin are taken off its free list with a single
+    /// exchange and then carved straight from its current chunk, instead of
+    /// one round trip per block. Only as many slots as are needed come off
+    /// the list, so the rest stay available to other threads sharing the
+    /// bin. Larger layouts fall back to one `alloc` per block. Tags,
+    /// hooks and the OOM handler see every block as if it was allocated
+    /// alone.
+    pub fn alloc_batch(&self, layout: Layout, out: &mut [*mut u8]) -> usize {
+        if layout.align() > MAX_ALIGN {
+            return 0;
+        }
+        let (_, bins) = self.current_bins();
+        if bins.for_size(layout.pad_to_align().size()).is_none() {
+            for (i, slot) in out.iter_mut().enumerate() {
+                *slot = unsafe { self.alloc(layout) };
+                if slot.is_null() {
+                    out[i..].fill(ptr::null_mut());
+                    return i;
+                }
+            }
+            return out.len();
+        }
+
+        #[cfg(not(feature = "std"))]
+        let wanted = out.len();
+        #[cfg(feature = "std")]
+        let mut tag = Tag::UNTAGGED;
+        #[cfg(feature = "std")]
+        let wanted = {
+            let mut wanted = 0;
+            while wanted < out.len() {
+                match self.tags.reserve(layout.size()) {
+                    Some(reserved) => tag = reserved,
+                    None => break,
+                }
+                wanted += 1;
+            }
+            wanted
+        };
+
+        let mut filled = 0;
+        let mut attempt = 0;
+        while filled < wanted {
+            let (cache, bins) = self.current_bins();
+            let bin = match bins.for_size(layout.pad_to_align().size()) {
+                Some(bin) => bin,
+                None => break,
+            };
+            let allocated =
+                unsafe { bin.alloc_many(&self.pages, &self.budget, &mut out[filled..wanted]) };
+            for &ptr in &out[filled..filled + allocated] {
+                #[cfg(feature = "std")]
+                self.tags.commit(tag, ptr, layout.size());
+                self.notify_alloc(ptr, layout, Some(cache));
+            }
+            filled += allocated;
+            // Only retries that get nothing count towards the bound
+            if allocated > 0 {
+                attempt = 0;
+            }
+            if filled < wanted && !self.handle_oom(layout.size(), attempt) {
+                break;
+            }
+            attempt += 1;
+        }
+        #[cfg(feature = "std")]
+        for _ in filled..wanted {
+            self.tags.commit(tag, ptr::null_mut(), layout.size());
+        }
+        for slot in &mut out[filled..] {
+            *slot = ptr::null_mut();
+        }
+        filled
+    }
+
+    /// Frees every block in `ptrs`, which must all have been allocated with
+    /// `layout`. Blocks that fit a bin go back on its free list in one go.
+    ///
+    /// # Safety
+    /// Each pointer must satisfy the contract of `GlobalAlloc::dealloc` for
+    /// `layout`.
+    pub unsafe fn dealloc_batch(&self, layout: Layout, ptrs: &[*mut u8]) {
+        let (cache, bins) = self.current_bins();
+        let bin = match bins.for_size(layout.pad_to_align().size()) {
+            Some(bin) => bin,
+            None => {
+                for &ptr in ptrs {
+                    self.dealloc(ptr, layout);
+                }
+                return;
+            }
+        };
+        #[cfg(feature = "std")]
+        for &ptr in ptrs {
+            self.tags.release(ptr, layout.size());
+        }
+        bin.dealloc_many(ptrs);
+        for &ptr in ptrs {
+            self.notify_dealloc(ptr, layout, Some(cache));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cmp::min,
+    mem, ptr, slice,
+};
+
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+
+/// A mapping owned by the allocator, such as a bin or arena chunk
+#[derive(Clone, Copy)]
+pub(crate) struct Chunk {
+    pub(crate) ptr: *mut u8,
+    pub(crate) len: usize,
+    /// Alignment it was mapped with
+    pub(crate) align: usize,
+    /// Chunks pushed onto the list before this one, which tells a mapping
+    /// apart from an earlier one at the same address
+    pub(crate) generation: usize,
+}
+
+impl Chunk {
+    /// The layout it was mapped with, to unmap it again
+    pub(crate) fn layout(&self) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(self.len, self.align) }
+    }
+}
+
+/// Growable list of mappings. The backing buffer comes straight from
+/// `PAGE_ALLOCATOR`, so pushing never recurses into the binned allocator.
+pub(crate) struct ChunkList {
+    ptr: *mut Chunk,
+    len: usize,
+    cap: usize,
+    pushed: usize,
+}
+
+unsafe impl Send for ChunkList {}
+
+impl ChunkList {
+    pub(crate) const fn new() -> Self {
+        Self {
+            ptr: ptr::null_mut(),
+            len: 0,
+            cap: 0,
+            pushed: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn as_slice(&self) -> &[Chunk] {
+        if self.ptr.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+
+    /// Returns false if the list couldn't grow
+    pub(crate) fn push(&mut self, ptr: *mut u8, layout: Layout) -> bool {
+        if self.len == self.cap && !self.grow() {
+            return false;
+        }
+        let chunk = Chunk {
+            ptr,
+            len: layout.size(),
+            align: layout.align(),
+            generation: self.pushed,
+        };
+        unsafe { self.ptr.add(self.len).write(chunk) };
+        self.len += 1;
+        self.pushed += 1;
+        true
+    }
+
+    /// Drops every mapping `keep` rejects, preserving the order of the rest
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Chunk) -> bool) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            let chunk = unsafe { *self.ptr.add(i) };
+            if keep(&chunk) {
+                unsafe { *self.ptr.add(kept) = chunk };
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+
+    /// Drops every mapping past the first `len`
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.len = min(self.len, len);
+    }
+
+    fn layout(cap: usize) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(cap * mem::size_of::<Chunk>(), *PAGE_SIZE) }
+    }
+
+    fn grow(&mut self) -> bool {
+        let new_cap = if self.cap == 0 {
+            *PAGE_SIZE / mem::size_of::<Chunk>()
+        } else {
+            self.cap * 2
+        };
+        let new_ptr = unsafe {
+            if self.ptr.is_null() {
+                PAGE_ALLOCATOR.alloc(Self::layout(new_cap))
+            } else {
+                PAGE_ALLOCATOR.realloc(
+                    self.ptr as *mut u8,
+                    Self::layout(self.cap),
+                    new_cap * mem::size_of::<Chunk>(),
+                )
+            }
+        } as *mut Chunk;
+        if new_ptr.is_null() {
+            return false;
+        }
+        self.ptr = new_ptr;
+        self.cap = new_cap;
+        true
+    }
+}
+
+impl Drop for ChunkList {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { PAGE_ALLOCATOR.dealloc(self.ptr as *mut u8, Self::layout(self.cap)) };
+        }
+    }
+}
@@ -0,0 +1,120 @@
+/// Number of CPU ids the kernel may report, online or not
+pub(crate) fn possible_cpus() -> usize {
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    if count < 1 {
+        1
+    } else {
+        count as usize
+    }
+}
+
+/// The CPU this thread is running on. It may have moved by the time the
+/// caller acts on it, which only costs some locality.
+#[inline]
+pub(crate) fn current_cpu() -> usize {
+    #[cfg(all(
+        target_env = "gnu",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    if let Some(cpu) = rseq::current_cpu() {
+        return cpu;
+    }
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        0
+    } else {
+        cpu as usize
+    }
+}
+
+#[cfg(all(
+    target_env = "gnu",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod rseq {
+    use core::sync::atomic::{AtomicIsize, Ordering};
+
+    /// States of `RSEQ_OFFSET` before it holds glibc's `__rseq_offset`. Real
+    /// offsets are small, so these can't clash with one.
+    const UNKNOWN: isize = isize::MIN;
+    const LOOKING: isize = isize::MIN + 1;
+    const UNAVAILABLE: isize = isize::MIN + 2;
+
+    static RSEQ_OFFSET: AtomicIsize = AtomicIsize::new(UNKNOWN);
+
+    /// Reads the CPU id the kernel keeps up to date in this thread's rseq area,
+    /// which is a plain load instead of a call into the vDSO
+    #[inline]
+    pub(super) fn current_cpu() -> Option<usize> {
+        let offset = match RSEQ_OFFSET.load(Ordering::Acquire) {
+            UNKNOWN => lookup_rseq_offset(),
+            offset => offset,
+        };
+        if offset == LOOKING || offset == UNAVAILABLE {
+            return None;
+        }
+        // `cpu_id` is the second field of `struct rseq`. It's negative until the
+        // area is registered.
+        let area = (thread_pointer() as isize + offset) as *const i32;
+        let cpu = unsafe { area.add(1).read_volatile() };
+        if cpu < 0 {
+            None
+        } else {
+            Some(cpu as usize)
+        }
+    }
+
+    /// glibc 2.35 and later register an rseq area for every thread and export
+    /// where it sits relative to the thread pointer. A failed `dlsym` allocates
+    /// its error message, so allocations made during the lookup fall back to
+    /// `sched_getcpu` rather than waiting for it.
+    #[cold]
+    fn lookup_rseq_offset() -> isize {
+        if RSEQ_OFFSET
+            .compare_exchange(UNKNOWN, LOOKING, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return LOOKING;
+        }
+        let offset = unsafe {
+            let offset = libc::dlsym(libc::RTLD_DEFAULT, b"__rseq_offset\0".as_ptr() as _);
+            let size = libc::dlsym(libc::RTLD_DEFAULT, b"__rseq_size\0".as_ptr() as _);
+            if offset.is_null() || size.is_null() || *(size as *const u32) == 0 {
+                UNAVAILABLE
+            } else {
+                *(offset as *const isize)
+            }
+        };
+        RSEQ_OFFSET.store(offset, Ordering::Release);
+        offset
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline]
+    fn thread_pointer() -> usize {
+        let tp: usize;
+        // The first word of the TCB points back at itself
+        unsafe {
+            core::arch::asm!(
+                "mov {}, qword ptr fs:[0]",
+                out(reg) tp,
+                options(nostack, readonly, preserves_flags)
+            )
+        };
+        tp
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[inline]
+    fn thread_pointer() -> usize {
+        let tp: usize;
+        unsafe {
+            core::arch::asm!(
+                "mrs {}, tpidr_el0",
+                out(reg) tp,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        tp
+    }
+}
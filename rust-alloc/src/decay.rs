@@ -0,0 +1,192 @@
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+    time::Duration,
+};
+use std::{
+    thread::{self, JoinHandle},
+    time::Instant,
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::{page_allocator::PageSource, trim::TrimMode, RSBMalloc, NUM_CLASSES};
+
+/// Background purging state of one allocator
+pub(crate) struct Decay {
+    millis: AtomicUsize,
+    stop: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// Set once the allocator is on the `RUNNING` list
+    registered: AtomicBool,
+    /// Next allocator's state on the `RUNNING` list
+    next: AtomicPtr<Decay>,
+}
+
+impl Decay {
+    pub(crate) const fn new() -> Self {
+        Self {
+            millis: AtomicUsize::new(0),
+            stop: AtomicBool::new(false),
+            thread: Mutex::new(None),
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Stops the thread, if it's running, and waits for it to finish its
+    /// current pass
+    fn stop(&self) {
+        let handle = self.thread.lock().take();
+        if let Some(handle) = handle {
+            self.stop.store(true, Ordering::Release);
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The decay state of every allocator that has ever started a decay thread,
+/// so they can all be stopped at exit
+static RUNNING: AtomicPtr<Decay> = AtomicPtr::new(ptr::null_mut());
+
+extern "C" fn stop_all_at_exit() {
+    let mut next = RUNNING.load(Ordering::Acquire);
+    while let Some(decay) = unsafe { next.as_ref() } {
+        decay.stop();
+        next = decay.next.load(Ordering::Acquire);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Free slots may still be resident
+    Dirty,
+    /// Free slots have been purged lazily
+    Muzzy,
+    Clean,
+}
+
+/// What the decay thread last saw of a bin
+#[derive(Clone, Copy)]
+struct BinDecay {
+    fingerprint: [usize; 3],
+    idle_since: Instant,
+    stage: Stage,
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Starts a thread that returns idle memory to the OS, similar to
+    /// jemalloc's `dirty_decay_ms`. Returns false if one is already running.
+    ///
+    /// Once a bin has gone untouched for `decay`, the pages inside its free
+    /// slots are purged lazily with `MADV_FREE`, so the OS only takes them
+    /// under memory pressure. After another `decay` without use, its free
+    /// chunks are unmapped and the remaining pages purged with
+    /// `MADV_DONTNEED`. The thread is stopped and joined when the process
+    /// exits.
+    pub fn start_decay(&'static self, decay: Duration) -> bool {
+        let mut thread = self.decay.thread.lock();
+        if thread.is_some() {
+            return false;
+        }
+        self.set_decay_time(decay);
+        self.decay.stop.store(false, Ordering::Release);
+        let spawned = thread::Builder::new()
+            .name("rsbmalloc-decay".into())
+            .spawn(move || self.run_decay());
+        match spawned {
+            Ok(handle) => *thread = Some(handle),
+            Err(_) => return false,
+        }
+        drop(thread);
+
+        if !self.decay.registered.swap(true, Ordering::AcqRel) {
+            let this = &self.decay as *const _ as *mut Decay;
+            let mut head = RUNNING.load(Ordering::Acquire);
+            loop {
+                self.decay.next.store(head, Ordering::Release);
+                match RUNNING.compare_exchange(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => break,
+                    Err(current) => head = current,
+                }
+            }
+            // Miri has no `atexit`, and its threads die with the process anyway
+            if head.is_null() && !cfg!(miri) {
+                unsafe { libc::atexit(stop_all_at_exit) };
+            }
+        }
+        true
+    }
+
+    /// Changes how long bins have to sit idle before the decay thread purges
+    /// them. Takes effect from the thread's next pass.
+    pub fn set_decay_time(&self, decay: Duration) {
+        let millis = decay.as_millis().min(usize::MAX as u128) as usize;
+        self.decay.millis.store(millis, Ordering::Relaxed);
+    }
+
+    /// Stops the decay thread and waits for it to finish its current pass.
+    /// Does nothing if it isn't running.
+    pub fn stop_decay(&self) {
+        self.decay.stop()
+    }
+
+    fn run_decay(&self) {
+        let mut bins = Vec::new();
+        while !self.decay.stop.load(Ordering::Acquire) {
+            let decay = Duration::from_millis(self.decay.millis.load(Ordering::Relaxed) as u64);
+            // Check a few times per decay period so bins aren't left much
+            // longer than asked
+            thread::park_timeout((decay / 4).max(Duration::from_millis(1)));
+            if self.decay.stop.load(Ordering::Acquire) {
+                break;
+            }
+            self.decay_pass(&mut bins, decay);
+        }
+    }
+
+    fn decay_pass(&self, states: &mut Vec<BinDecay>, decay: Duration) {
+        let now = Instant::now();
+        self.for_each_bins(|cache, bins| {
+            let first = cache * NUM_CLASSES;
+            if states.len() < first + NUM_CLASSES {
+                states.resize(
+                    first + NUM_CLASSES,
+                    BinDecay {
+                        fingerprint: [0; 3],
+                        idle_since: now,
+                        stage: Stage::Clean,
+                    },
+                );
+            }
+            let bins = states[first..].iter_mut().zip(bins.as_array());
+            for (class, (state, bin)) in bins.enumerate() {
+                let fingerprint = bin.fingerprint();
+                if fingerprint != state.fingerprint {
+                    *state = BinDecay {
+                        fingerprint,
+                        idle_since: now,
+                        stage: Stage::Dirty,
+                    };
+                    continue;
+                }
+                let idle = now.duration_since(state.idle_since);
+                let mode = match state.stage {
+                    Stage::Dirty if idle >= decay => TrimMode::Lazy,
+                    Stage::Muzzy if idle >= decay * 2 => TrimMode::Eager,
+                    _ => continue,
+                };
+                bin.trim(&mut 0, &self.pages, &self.budget, mode, &|visit| {
+                    self.class_lists(class, visit)
+                });
+                state.fingerprint = bin.fingerprint();
+                state.stage = match mode {
+                    TrimMode::Lazy => Stage::Muzzy,
+                    TrimMode::Eager => Stage::Clean,
+                };
+            }
+        });
+    }
+}
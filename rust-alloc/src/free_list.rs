@@ -0,0 +1,384 @@
+use core::ptr;
+
+use crate::sync::{
+    atomic::{fence, AtomicUsize, Ordering},
+    spin_loop,
+};
+
+/// The free slot linked after `slot`, stored in its first word
+#[inline(always)]
+pub(crate) unsafe fn next(slot: *mut u8) -> *mut u8 {
+    load_link(slot) as *mut u8
+}
+
+#[inline(always)]
+pub(crate) unsafe fn set_next(slot: *mut u8, next: *mut u8) {
+    store_link(slot, next as usize)
+}
+
+/// Links are read and written atomically, because a pop may read one while
+/// another thread is taking the same slot
+#[inline(always)]
+unsafe fn load_link(slot: *mut u8) -> usize {
+    (*(slot as *const core::sync::atomic::AtomicUsize)).load(Ordering::Relaxed)
+}
+
+#[inline(always)]
+unsafe fn store_link(slot: *mut u8, link: usize) {
+    (*(slot as *const core::sync::atomic::AtomicUsize)).store(link, Ordering::Relaxed)
+}
+
+/// How a free list records slots, both in its head and in each slot's link
+pub(crate) trait Links: Copy {
+    unsafe fn next(self, slot: *mut u8) -> *mut u8;
+    unsafe fn set_next(self, slot: *mut u8, next: *mut u8);
+    /// `slot`, or null, as the number kept in the head
+    fn encode(self, slot: *mut u8) -> usize;
+    fn decode(self, word: usize) -> *mut u8;
+}
+
+/// Slots recorded by address
+#[derive(Clone, Copy)]
+pub(crate) struct Addresses;
+
+impl Links for Addresses {
+    #[inline(always)]
+    unsafe fn next(self, slot: *mut u8) -> *mut u8 {
+        next(slot)
+    }
+
+    #[inline(always)]
+    unsafe fn set_next(self, slot: *mut u8, next: *mut u8) {
+        set_next(slot, next)
+    }
+
+    #[inline(always)]
+    fn encode(self, slot: *mut u8) -> usize {
+        slot as usize
+    }
+
+    #[inline(always)]
+    fn decode(self, word: usize) -> *mut u8 {
+        word as *mut u8
+    }
+}
+
+/// Slots recorded by their offset from the start of a mapping, which
+/// processes sharing it may see at different addresses. Offset 0 stands for
+/// null, so no slot may start there.
+#[cfg_attr(not(all(unix, feature = "std", not(loom))), allow(dead_code))]
+#[derive(Clone, Copy)]
+pub(crate) struct Offsets(pub(crate) *mut u8);
+
+impl Links for Offsets {
+    unsafe fn next(self, slot: *mut u8) -> *mut u8 {
+        self.decode(load_link(slot))
+    }
+
+    unsafe fn set_next(self, slot: *mut u8, next: *mut u8) {
+        store_link(slot, self.encode(next))
+    }
+
+    fn encode(self, slot: *mut u8) -> usize {
+        if slot.is_null() {
+            0
+        } else {
+            (slot as usize).wrapping_sub(self.0 as usize)
+        }
+    }
+
+    fn decode(self, word: usize) -> *mut u8 {
+        if word == 0 {
+            ptr::null_mut()
+        } else {
+            self.0.wrapping_add(word)
+        }
+    }
+}
+
+/// Lock-free stack of free slots (a Treiber stack), linked through the first
+/// word of each slot.
+///
+/// The head carries a tag that's bumped on every update, so a pop that read
+/// a slot's link just before another thread popped the slot and pushed it
+/// back fails its compare-exchange instead of installing a stale link (the
+/// ABA problem).
+pub(crate) struct FreeList {
+    head: head::Head,
+    /// Pops that may still read the link of a slot they don't own, counted
+    /// by the parity of `epoch` when they started. See `wait_for_pops`.
+    poppers: [AtomicUsize; 2],
+    epoch: AtomicUsize,
+}
+
+impl FreeList {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Self {
+                head: head::Head::new(),
+                poppers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                epoch: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    /// Takes a slot off the list, or returns null if it's empty
+    pub(crate) unsafe fn pop(&self) -> *mut u8 {
+        self.pop_in(Addresses)
+    }
+
+    /// `pop`, for a list whose slots are recorded with `links`
+    pub(crate) unsafe fn pop_in<L: Links>(&self, links: L) -> *mut u8 {
+        let poppers = self.enter();
+        let mut head = self.head.load();
+        let slot = loop {
+            let slot = links.decode(head::ptr(head));
+            if slot.is_null() {
+                break slot;
+            }
+            // The slot may be popped, handed out and written to by another
+            // thread before this read. The link is garbage then, but it's
+            // read atomically, and the tag has moved on, so the exchange
+            // below fails and it's discarded.
+            let next = links.next(slot);
+            match self.head.compare_exchange(head, links.encode(next)) {
+                Ok(_) => break slot,
+                Err(current) => head = current,
+            }
+        };
+        poppers.fetch_sub(1, Ordering::Release);
+        slot
+    }
+
+    /// Takes up to `out.len()` slots off the list with a single exchange,
+    /// returning how many were taken. The rest of the list stays put.
+    pub(crate) unsafe fn pop_many(&self, out: &mut [*mut u8]) -> usize {
+        if out.is_empty() {
+            return 0;
+        }
+        let poppers = self.enter();
+        let mut head = self.head.load();
+        let taken = 'retry: loop {
+            let mut slot = head::ptr(head) as *mut u8;
+            let mut taken = 0;
+            while !slot.is_null() && taken < out.len() {
+                out[taken] = slot;
+                taken += 1;
+                slot = next(slot);
+                // Once the list has changed, `slot` may be a stale link
+                // pointing anywhere, so check before following it
+                if !slot.is_null() && taken < out.len() {
+                    fence(Ordering::Acquire);
+                    let current = self.head.load();
+                    if current != head {
+                        head = current;
+                        continue 'retry;
+                    }
+                }
+            }
+            match self.head.compare_exchange(head, slot as usize) {
+                Ok(_) => break taken,
+                Err(current) => head = current,
+            }
+        };
+        poppers.fetch_sub(1, Ordering::Release);
+        taken
+    }
+
+    /// Counts the caller among the pops in flight until it takes itself off
+    /// the returned counter
+    fn enter(&self) -> &AtomicUsize {
+        loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let poppers = &self.poppers[epoch & 1];
+            poppers.fetch_add(1, Ordering::SeqCst);
+            // A grace period that flipped the epoch in between may already
+            // have seen this counter at zero
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                return poppers;
+            }
+            poppers.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    pub(crate) unsafe fn push(&self, slot: *mut u8) {
+        self.push_chain(slot, slot)
+    }
+
+    /// Pushes the already linked slots `first..=last` in one go
+    pub(crate) unsafe fn push_chain(&self, first: *mut u8, last: *mut u8) {
+        self.push_chain_in(Addresses, first, last)
+    }
+
+    /// `push_chain`, for a list whose slots are recorded with `links`
+    pub(crate) unsafe fn push_chain_in<L: Links>(&self, links: L, first: *mut u8, last: *mut u8) {
+        let mut head = self.head.load();
+        loop {
+            links.set_next(last, links.decode(head::ptr(head)));
+            match self.head.compare_exchange(head, links.encode(first)) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Empties the list, returning its first slot. The slots stay linked, and
+    /// belong to the caller until handed back with `give_back`, though pops
+    /// may read their links until `wait_for_pops` returns.
+    pub(crate) fn take_all(&self) -> *mut u8 {
+        let mut head = self.head.load();
+        loop {
+            match self.head.compare_exchange(head, 0) {
+                Ok(_) => return head::ptr(head) as *mut u8,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Pushes back a chain of slots taken with `take_all`, ahead of anything
+    /// freed in the meantime
+    pub(crate) unsafe fn give_back(&self, first: *mut u8) {
+        if first.is_null() {
+            return;
+        }
+        // If nothing was freed meanwhile, the chain can go back as is
+        // without looking for its end
+        let head = self.head.load();
+        if head::ptr(head) == 0 && self.head.compare_exchange(head, first as usize).is_ok() {
+            return;
+        }
+        let mut last = first;
+        while !next(last).is_null() {
+            last = next(last);
+        }
+        self.push_chain(first, last)
+    }
+
+    /// Changes on every push and pop
+    #[cfg(feature = "std")]
+    pub(crate) fn version(&self) -> usize {
+        head::version(self.head.load())
+    }
+}
+
+/// Calls the function it's given with each of a set of free lists
+pub(crate) type Lists<'a> = dyn Fn(&mut dyn FnMut(&FreeList)) + 'a;
+
+/// Serializes grace periods, so the pops a grace period waits for all
+/// started before it
+static GRACE: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Waits until no pop on the lists that `lists` visits can still be reading
+/// a slot detached before the call, so the slot's memory can be unmapped.
+/// `lists` must visit every list the slot could have been on: a slot goes
+/// back on whichever list the freeing thread uses, so a pop on any of them
+/// may be about to read it. Pops that start later don't hold this up.
+pub(crate) fn wait_for_pops(lists: &Lists) {
+    let _grace = GRACE.lock();
+    lists(&mut |list| {
+        list.epoch.fetch_add(1, Ordering::SeqCst);
+    });
+    lists(&mut |list| {
+        let before = list.epoch.load(Ordering::SeqCst).wrapping_sub(1);
+        while list.poppers[before & 1].load(Ordering::SeqCst) != 0 {
+            spin_loop();
+        }
+    });
+}
+
+/// The head word packs the first slot's address with a tag. With 64-bit
+/// atomics, that's a 16-bit tag above a 48-bit address on 64-bit targets, or
+/// a 32-bit tag on 32-bit ones such as `armv7`, `i686` and `wasm32`. This is
+/// the lock-free path.
+#[cfg(target_has_atomic = "64")]
+mod head {
+    use crate::sync::atomic::{AtomicU64, Ordering};
+
+    #[cfg(target_pointer_width = "64")]
+    const PTR_BITS: u32 = 48;
+    #[cfg(not(target_pointer_width = "64"))]
+    const PTR_BITS: u32 = usize::BITS;
+    const PTR_MASK: u64 = (1 << PTR_BITS) - 1;
+
+    pub(super) type Word = u64;
+
+    pub(super) struct Head(AtomicU64);
+
+    impl Head {
+        const_fn! {
+            pub(super) fn new() -> Self {
+                Self(AtomicU64::new(0))
+            }
+        }
+
+        pub(super) fn load(&self) -> Word {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        /// Points the head at `ptr` and bumps the tag, if it's still `current`.
+        /// A pop that lost a race may pass a garbage `ptr`, so it's only
+        /// checked once the exchange has gone through.
+        pub(super) fn compare_exchange(&self, current: Word, ptr: usize) -> Result<Word, Word> {
+            let tag = (current >> PTR_BITS).wrapping_add(1);
+            let new = tag << PTR_BITS | ptr as u64 & PTR_MASK;
+            let result =
+                self.0
+                    .compare_exchange_weak(current, new, Ordering::SeqCst, Ordering::Acquire);
+            debug_assert!(result.is_err() || ptr as u64 & !PTR_MASK == 0);
+            result
+        }
+    }
+
+    pub(super) fn ptr(word: Word) -> usize {
+        (word & PTR_MASK) as usize
+    }
+
+    #[cfg(feature = "std")]
+    pub(super) fn version(word: Word) -> usize {
+        word as usize ^ (word >> 32) as usize
+    }
+}
+
+/// Without 64-bit atomics there's no room for a tag beside the address, so
+/// the head falls back to a spinlock around the address and a counter. That
+/// covers most microcontrollers, such as `thumbv7em`, `thumbv8m` and
+/// `riscv32imac`; the lock is only held for the exchange itself.
+#[cfg(not(target_has_atomic = "64"))]
+mod head {
+    use crate::sync::Mutex;
+
+    pub(super) type Word = (usize, usize);
+
+    pub(super) struct Head(Mutex<Word>);
+
+    impl Head {
+        const_fn! {
+            pub(super) fn new() -> Self {
+                Self(Mutex::new((0, 0)))
+            }
+        }
+
+        pub(super) fn load(&self) -> Word {
+            *self.0.lock()
+        }
+
+        pub(super) fn compare_exchange(&self, current: Word, ptr: usize) -> Result<Word, Word> {
+            let mut head = self.0.lock();
+            if *head == current {
+                *head = (ptr, current.1.wrapping_add(1));
+                Ok(current)
+            } else {
+                Err(*head)
+            }
+        }
+    }
+
+    pub(super) fn ptr(word: Word) -> usize {
+        word.0
+    }
+
+    #[cfg(feature = "std")]
+    pub(super) fn version(word: Word) -> usize {
+        word.0 ^ word.1
+    }
+}
@@ -0,0 +1,44 @@
+#[cfg(feature = "std")]
+mod imp {
+    use core::cell::Cell;
+    use std::thread::LocalKey;
+
+    pub(crate) type Flag = LocalKey<Cell<bool>>;
+
+    std::thread_local! {
+        pub(crate) static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+        pub(crate) static IN_OOM_HANDLER: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Runs `f` unless this thread is already inside it
+    pub(crate) fn enter<R>(flag: &'static Flag, f: impl FnOnce() -> R) -> Option<R> {
+        if flag.try_with(|flag| flag.replace(true)).unwrap_or(true) {
+            return None;
+        }
+        let result = f();
+        let _ = flag.try_with(|flag| flag.set(false));
+        Some(result)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    pub(crate) type Flag = AtomicBool;
+
+    pub(crate) static IN_HOOK: AtomicBool = AtomicBool::new(false);
+    pub(crate) static IN_OOM_HANDLER: AtomicBool = AtomicBool::new(false);
+
+    /// Runs `f` unless any thread is already inside it
+    pub(crate) fn enter<R>(flag: &'static Flag, f: impl FnOnce() -> R) -> Option<R> {
+        if flag.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        let result = f();
+        flag.store(false, Ordering::Release);
+        Some(result)
+    }
+}
+
+pub(crate) use imp::{enter, IN_HOOK, IN_OOM_HANDLER};
@@ -0,0 +1,106 @@
+use core::alloc::Layout;
+
+use crate::{guard, page_allocator::PageSource, RSBMalloc};
+
+/// An allocation or deallocation, as seen by `AllocHooks`
+#[derive(Clone, Copy, Debug)]
+pub struct AllocEvent {
+    pub ptr: *mut u8,
+    pub layout: Layout,
+    /// Thread cache that served the request (always 0 without `std`), or
+    /// `None` for large allocations
+    pub cache: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReallocEvent {
+    pub old_ptr: *mut u8,
+    pub old_layout: Layout,
+    pub new_ptr: *mut u8,
+    pub new_size: usize,
+    /// Thread cache that served the new block, or `None` for large
+    /// allocations
+    pub cache: Option<usize>,
+}
+
+/// Receives every successful allocation, deallocation and reallocation.
+///
+/// Hooks may allocate: anything they allocate goes through the allocator as
+/// usual but isn't reported back to them. With `std` this is tracked per
+/// thread; without it, a single flag covers every thread, so events on other
+/// threads are dropped while a hook is running.
+pub trait AllocHooks: Sync {
+    fn on_alloc(&self, _event: AllocEvent) {}
+    /// Runs after the block has been released
+    fn on_dealloc(&self, _event: AllocEvent) {}
+    fn on_realloc(&self, _event: ReallocEvent) {}
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Installs `hooks` for the lifetime of the allocator. Meant to be called
+    /// once, before the first allocation; returns false if hooks were already
+    /// installed.
+    pub fn set_hooks(&self, hooks: &'static dyn AllocHooks) -> bool {
+        let mut installed = false;
+        self.hooks.call_once(|| {
+            installed = true;
+            hooks
+        });
+        installed
+    }
+
+    #[inline]
+    pub(crate) fn notify_alloc(&self, ptr: *mut u8, layout: Layout, cache: Option<usize>) {
+        if ptr.is_null() {
+            return;
+        }
+        #[cfg(feature = "backtrace")]
+        self.record_alloc(ptr, layout.size());
+        if let Some(hooks) = self.hooks.get() {
+            guard::enter(&guard::IN_HOOK, || {
+                hooks.on_alloc(AllocEvent { ptr, layout, cache })
+            });
+        }
+    }
+
+    #[inline]
+    pub(crate) fn notify_dealloc(&self, ptr: *mut u8, layout: Layout, cache: Option<usize>) {
+        #[cfg(feature = "backtrace")]
+        self.forget_alloc(ptr);
+        if let Some(hooks) = self.hooks.get() {
+            guard::enter(&guard::IN_HOOK, || {
+                hooks.on_dealloc(AllocEvent { ptr, layout, cache })
+            });
+        }
+    }
+
+    #[inline]
+    pub(crate) fn notify_realloc(
+        &self,
+        old_ptr: *mut u8,
+        old_layout: Layout,
+        new_ptr: *mut u8,
+        new_size: usize,
+        cache: Option<usize>,
+    ) {
+        if new_ptr.is_null() {
+            return;
+        }
+        #[cfg(feature = "backtrace")]
+        {
+            self.forget_alloc(old_ptr);
+            self.record_alloc(new_ptr, new_size);
+        }
+        if let Some(hooks) = self.hooks.get() {
+            guard::enter(&guard::IN_HOOK, || {
+                hooks.on_realloc(ReallocEvent {
+                    old_ptr,
+                    old_layout,
+                    new_ptr,
+                    new_size,
+                    cache,
+                })
+            });
+        }
+    }
+}
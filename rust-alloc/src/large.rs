@@ -0,0 +1,59 @@
+use crate::{
+    side_table::SideTable,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Allocations too big for a bin, by address, with their sizes. The totals
+/// are kept apart in atomics so stats can read them without locking.
+pub(crate) struct LargeMap {
+    table: SideTable<usize>,
+    mappings: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl LargeMap {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Self {
+                table: SideTable::new(),
+                mappings: AtomicUsize::new(0),
+                bytes: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    /// Returns false if the table couldn't grow
+    pub(crate) fn insert(&self, ptr: *mut u8, len: usize) -> bool {
+        if !self.table.insert(ptr as usize, len) {
+            return false;
+        }
+        self.mappings.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len, Ordering::Relaxed);
+        true
+    }
+
+    pub(crate) fn remove(&self, ptr: *mut u8) {
+        if let Some(len) = self.table.remove(ptr as usize) {
+            self.mappings.fetch_sub(1, Ordering::Relaxed);
+            self.bytes.fetch_sub(len, Ordering::Relaxed);
+        }
+    }
+
+    /// The length of the mapping starting at `ptr`
+    pub(crate) fn get(&self, ptr: *const u8) -> Option<usize> {
+        self.table.get(ptr as usize)
+    }
+
+    /// Visits every mapping with its shard of the table locked
+    pub(crate) fn for_each(&self, mut f: impl FnMut(*mut u8, usize)) {
+        self.table.for_each(|ptr, &len| f(ptr as *mut u8, len))
+    }
+
+    /// Mappings and bytes, without locking
+    pub(crate) fn totals(&self) -> (usize, usize) {
+        (
+            self.mappings.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+}
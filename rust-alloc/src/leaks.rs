@@ -0,0 +1,237 @@
+use core::fmt;
+
+use crate::{page_allocator::PageSource, RSBMalloc, NUM_CLASSES};
+
+/// Blocks still allocated in one size class
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassLeaks {
+    /// Largest allocation the class serves, or 0 for large allocations
+    pub size: usize,
+    pub blocks: usize,
+    /// Bytes held by those blocks, including slot padding
+    pub bytes: usize,
+}
+
+/// Everything still allocated, grouped by size class
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeakSummary {
+    pub classes: [ClassLeaks; NUM_CLASSES],
+    pub large: ClassLeaks,
+}
+
+impl LeakSummary {
+    pub fn blocks(&self) -> usize {
+        self.classes.iter().map(|class| class.blocks).sum::<usize>() + self.large.blocks
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.classes.iter().map(|class| class.bytes).sum::<usize>() + self.large.bytes
+    }
+}
+
+impl fmt::Display for LeakSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>10} {:>12} {:>14}", "size", "live blocks", "bytes")?;
+        for class in self.classes.iter().filter(|class| class.blocks > 0) {
+            writeln!(
+                f,
+                "{:>10} {:>12} {:>14}",
+                class.size, class.blocks, class.bytes
+            )?;
+        }
+        if self.large.blocks > 0 {
+            writeln!(
+                f,
+                "{:>10} {:>12} {:>14}",
+                "large", self.large.blocks, self.large.bytes
+            )?;
+        }
+        writeln!(
+            f,
+            "{:>10} {:>12} {:>14}",
+            "total",
+            self.blocks(),
+            self.bytes()
+        )
+    }
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Counts the blocks that are still allocated across every bin and large
+    /// mapping. Meant for debug builds, once the program has released
+    /// everything it intends to.
+    pub fn leaks(&self) -> LeakSummary {
+        let mut summary = LeakSummary::default();
+        self.for_each_bins(|_, bins| {
+            for (class, bin) in summary.classes.iter_mut().zip(bins.as_array()) {
+                let used = bin.usage().used();
+                class.size = bin.class_size();
+                class.blocks += used;
+                class.bytes += used * bin.slot_size();
+            }
+        });
+        let (blocks, bytes) = self.large.totals();
+        summary.large.blocks = blocks;
+        summary.large.bytes = bytes;
+        summary
+    }
+
+    /// Writes the leak summary, followed by the allocation site of every live
+    /// block when the `backtrace` feature is on
+    pub fn report_leaks<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        writeln!(out, "rsbmalloc leak report")?;
+        write!(out, "{}", self.leaks())?;
+        #[cfg(feature = "backtrace")]
+        self.report_traces(out)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn print_leaks(&self) {
+        let mut report = std::string::String::new();
+        if self.report_leaks(&mut report).is_ok() {
+            std::eprint!("{}", report);
+        }
+    }
+
+    /// Prints the leak report when the process exits. Only the most recently
+    /// registered allocator is reported. Nothing is printed under Miri,
+    /// which has no `atexit`.
+    #[cfg(feature = "std")]
+    pub fn report_leaks_at_exit(&'static self) {
+        let previous = AT_EXIT.lock().replace(self);
+        if previous.is_none() && !cfg!(miri) {
+            unsafe { libc::atexit(print_leaks_at_exit) };
+        }
+    }
+}
+
+/// An allocator whose leaks can be printed, whatever its page source
+#[cfg(feature = "std")]
+trait PrintLeaks: Sync {
+    fn print_leaks(&self);
+}
+
+#[cfg(feature = "std")]
+impl<P: PageSource> PrintLeaks for RSBMalloc<P> {
+    fn print_leaks(&self) {
+        RSBMalloc::print_leaks(self)
+    }
+}
+
+#[cfg(feature = "std")]
+static AT_EXIT: spin::Mutex<Option<&'static dyn PrintLeaks>> = spin::Mutex::new(None);
+
+#[cfg(feature = "std")]
+extern "C" fn print_leaks_at_exit() {
+    let allocator = *AT_EXIT.lock();
+    if let Some(allocator) = allocator {
+        allocator.print_leaks();
+    }
+}
+
+#[cfg(feature = "backtrace")]
+pub(crate) use traces::Trace;
+
+#[cfg(feature = "backtrace")]
+mod traces {
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::Cell,
+        fmt,
+    };
+
+    use crate::{
+        page_allocator::{PageSource, PAGE_ALLOCATOR},
+        RSBMalloc,
+    };
+
+    const TRACE_DEPTH: usize = 16;
+    /// `capture` and the allocator's own frames
+    const SKIP_FRAMES: usize = 3;
+
+    #[derive(Clone, Copy)]
+    pub(crate) struct Trace {
+        size: usize,
+        frames: [usize; TRACE_DEPTH],
+    }
+
+    std::thread_local! {
+        /// Set while capturing, so allocations made by the unwinder aren't
+        /// traced themselves
+        static CAPTURING: Cell<bool> = const { Cell::new(false) };
+    }
+
+    impl<P: PageSource> RSBMalloc<P> {
+        pub(crate) fn record_alloc(&self, ptr: *mut u8, size: usize) {
+            if ptr.is_null() || CAPTURING.try_with(|c| c.replace(true)).unwrap_or(true) {
+                return;
+            }
+            let mut trace = Trace {
+                size,
+                frames: [0; TRACE_DEPTH],
+            };
+            let mut depth = 0;
+            backtrace::trace(|frame| {
+                if depth >= SKIP_FRAMES {
+                    trace.frames[depth - SKIP_FRAMES] = frame.ip() as usize;
+                }
+                depth += 1;
+                depth < TRACE_DEPTH + SKIP_FRAMES
+            });
+            self.traces.insert(ptr as usize, trace);
+            let _ = CAPTURING.try_with(|c| c.set(false));
+        }
+
+        pub(crate) fn forget_alloc(&self, ptr: *mut u8) {
+            self.traces.remove(ptr as usize);
+        }
+
+        /// Copies the live traces out of the side table before resolving
+        /// symbols, since resolving allocates
+        pub(super) fn report_traces<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+            let len = self.traces.len() + 64;
+            let layout = Layout::array::<(usize, Trace)>(len).map_err(|_| fmt::Error)?;
+            let buf = unsafe { PAGE_ALLOCATOR.alloc(layout) } as *mut (usize, Trace);
+            if buf.is_null() {
+                return Err(fmt::Error);
+            }
+            let mut count = 0;
+            self.traces.for_each(|ptr, trace| {
+                if count < len {
+                    unsafe { buf.add(count).write((ptr, *trace)) };
+                    count += 1;
+                }
+            });
+            let traces = unsafe { core::slice::from_raw_parts(buf, count) };
+            let result = traces
+                .iter()
+                .try_for_each(|(ptr, trace)| write_trace(out, *ptr, trace));
+            unsafe { PAGE_ALLOCATOR.dealloc(buf as *mut u8, layout) };
+            result
+        }
+    }
+
+    fn write_trace<W: fmt::Write>(out: &mut W, ptr: usize, trace: &Trace) -> fmt::Result {
+        writeln!(out, "\n{} bytes at {:#x} allocated at:", trace.size, ptr)?;
+        for &ip in trace.frames.iter().take_while(|&&ip| ip != 0) {
+            let mut resolved = false;
+            let mut result = Ok(());
+            backtrace::resolve(ip as *mut _, |symbol| {
+                resolved = true;
+                result = match (symbol.name(), symbol.filename(), symbol.lineno()) {
+                    (Some(name), Some(file), Some(line)) => {
+                        writeln!(out, "    {} ({}:{})", name, file.display(), line)
+                    }
+                    (Some(name), _, _) => writeln!(out, "    {}", name),
+                    _ => writeln!(out, "    {:#x}", ip),
+                };
+            });
+            result?;
+            if !resolved {
+                writeln!(out, "    {:#x}", ip)?;
+            }
+        }
+        Ok(())
+    }
+}
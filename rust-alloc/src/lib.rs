@@ -1,367 +1,710 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 
-#[cfg(not(feature = "std"))]
-use core::ptr;
 use core::{
     alloc::{GlobalAlloc, Layout},
     cmp::min,
     mem,
-    ptr::NonNull,
 };
 
-use page_allocator::PAGE_ALLOCATOR;
+use chunks::ChunkList;
+use free_list::FreeList;
+use limit::Budget;
+use page_allocator::{PageAllocator, PageSource};
 use spin::Mutex;
+use sync::atomic::{AtomicUsize, Ordering};
 #[cfg(feature = "std")]
 use thread_cache::ThreadCache;
 
+/// Declares a `const fn`, except under loom, whose primitives can't be
+/// built in a const context
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])* $vis const fn $($rest)*
+        #[cfg(loom)]
+        $(#[$attr])* $vis fn $($rest)*
+    };
+}
+
+mod arena;
+mod batch;
+mod chunks;
+#[cfg(all(feature = "percpu", target_os = "linux"))]
+mod cpu;
+#[cfg(feature = "std")]
+mod decay;
+mod free_list;
+mod guard;
+mod hooks;
+mod large;
+mod leaks;
+mod limit;
 pub mod page_allocator;
+mod pool;
+mod segment;
+#[cfg(all(unix, feature = "std", not(loom)))]
+mod shared;
+mod side_table;
+mod stats;
+mod sync;
+#[cfg(feature = "std")]
+mod tags;
 #[cfg(feature = "std")]
 mod thread_cache;
+mod trim;
+mod walk;
+
+pub use arena::{Arena, Checkpoint};
+pub use hooks::{AllocEvent, AllocHooks, ReallocEvent};
+pub use leaks::{ClassLeaks, LeakSummary};
+pub use limit::{OomHandler, MAX_OOM_RETRIES};
+pub use pool::{Pool, PoolBox};
+#[cfg(all(unix, feature = "std", not(loom)))]
+pub use shared::RSBSharedHeap;
+pub use stats::{ClassStats, Stats, StatsFormat};
+#[cfg(feature = "std")]
+pub use tags::{current_tag, with_tag, Tag, MAX_TAGS};
+pub use walk::{BlockInfo, BlockState};
 
-#[cfg(test)]
-#[cfg_attr(test, global_allocator)]
+// Under Miri, std's own blocks come from the system allocator: a pop that
+// lost a race can read the first word of a block just handed out, which Miri
+// reports against std's plain writes there
+#[cfg(all(test, not(loom), not(miri)))]
+#[global_allocator]
 static BINNED_ALLOC: RSBMalloc = RSBMalloc::new();
 
 const RSB_CHUNK_SIZE: usize = 0x10000;
 const MAX_ALIGN: usize = 0x1000;
 
-pub struct RSBMalloc {
+/// The allocator, taking the memory it hands out from the page source `P`,
+/// by default the OS through `PAGE_ALLOCATOR`
+pub struct RSBMalloc<P = PageAllocator> {
     #[cfg(not(feature = "std"))]
     bins: Bins,
     #[cfg(feature = "std")]
     thread_cache: ThreadCache,
+    /// Allocations too big for a bin, taken straight from `pages`
+    large: large::LargeMap,
+    pages: P,
+    budget: Budget,
+    oom_handler: Mutex<Option<OomHandler>>,
+    hooks: spin::Once<&'static dyn AllocHooks>,
+    #[cfg(feature = "std")]
+    tags: tags::Tags,
+    #[cfg(feature = "std")]
+    decay: decay::Decay,
+    #[cfg(feature = "backtrace")]
+    traces: side_table::SideTable<leaks::Trace>,
 }
 
 impl RSBMalloc {
-    pub const fn new() -> Self {
-        Self {
-            #[cfg(not(feature = "std"))]
-            bins: Bins::new(),
-            #[cfg(feature = "std")]
-            thread_cache: ThreadCache::new(),
+    const_fn! {
+        pub fn new() -> Self {
+            Self::with_page_source(PageAllocator {})
+        }
+    }
+}
+
+impl<P> RSBMalloc<P> {
+    const_fn! {
+        /// An allocator taking its chunks and large allocations from `pages`
+        /// instead of the OS
+        pub fn with_page_source(pages: P) -> Self {
+            Self {
+                #[cfg(not(feature = "std"))]
+                bins: Bins::new(),
+                #[cfg(feature = "std")]
+                thread_cache: ThreadCache::new(),
+                large: large::LargeMap::new(),
+                pages,
+                budget: Budget::new(),
+                oom_handler: Mutex::new(None),
+                hooks: spin::Once::new(),
+                #[cfg(feature = "std")]
+                tags: tags::Tags::new(),
+                #[cfg(feature = "std")]
+                decay: decay::Decay::new(),
+                #[cfg(feature = "backtrace")]
+                traces: side_table::SideTable::new(),
+            }
+        }
+    }
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// The bins serving the calling thread, along with their index
+    #[cfg(not(feature = "std"))]
+    fn current_bins(&self) -> (usize, &Bins) {
+        (0, &self.bins)
+    }
+
+    #[cfg(feature = "std")]
+    fn current_bins(&self) -> (usize, &Bins) {
+        unsafe { self.thread_cache.get_thread_cache() }
+    }
+
+    /// Calls `f` with the index and contents of every set of bins
+    #[cfg(not(feature = "std"))]
+    fn for_each_bins(&self, mut f: impl FnMut(usize, &Bins)) {
+        f(0, &self.bins)
+    }
+
+    /// Calls `f` with the index and contents of every thread cache
+    #[cfg(feature = "std")]
+    fn for_each_bins(&self, f: impl FnMut(usize, &Bins)) {
+        self.thread_cache.for_each(f)
+    }
+
+    unsafe fn alloc_large(&self, layout: Layout) -> *mut u8 {
+        let mapped = limit::mapped_size(layout.size());
+        if !self.budget.try_charge(mapped) {
+            return core::ptr::null_mut();
+        }
+        let ptr = self.pages.alloc_pages(layout);
+        if ptr.is_null() {
+            self.budget.refund(mapped);
+        } else if !self.large.insert(ptr, layout.size()) {
+            self.pages.dealloc_pages(ptr, layout);
+            self.budget.refund(mapped);
+            return core::ptr::null_mut();
         }
+        ptr
+    }
+
+    unsafe fn dealloc_large(&self, ptr: *mut u8, layout: Layout) {
+        self.large.remove(ptr);
+        self.pages.dealloc_pages(ptr, layout);
+        self.budget.refund(limit::mapped_size(layout.size()));
+    }
+
+    unsafe fn realloc_large(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_mapped = limit::mapped_size(layout.size());
+        let new_mapped = limit::mapped_size(new_size);
+        if new_mapped > old_mapped && !self.budget.try_charge(new_mapped - old_mapped) {
+            return core::ptr::null_mut();
+        }
+        let new_ptr = self.pages.realloc_pages(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            if new_mapped > old_mapped {
+                self.budget.refund(new_mapped - old_mapped);
+            }
+        } else {
+            self.large.remove(ptr);
+            self.large.insert(new_ptr, new_size);
+            if new_mapped < old_mapped {
+                self.budget.refund(old_mapped - new_mapped);
+            }
+        }
+        new_ptr
     }
 }
 
-#[cfg(not(feature = "std"))]
-unsafe impl GlobalAlloc for RSBMalloc {
+impl<P: Default> Default for RSBMalloc<P> {
+    fn default() -> Self {
+        Self::with_page_source(P::default())
+    }
+}
+
+unsafe impl<P: PageSource> GlobalAlloc for RSBMalloc<P> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         if layout.align() > MAX_ALIGN {
-            return ptr::null_mut();
-        }
-        let size = layout.pad_to_align().size();
-        match size {
-            ..=4 => self.bins.bin4.alloc(),
-            ..=8 => self.bins.bin8.alloc(),
-            ..=16 => self.bins.bin16.alloc(),
-            ..=32 => self.bins.bin32.alloc(),
-            ..=64 => self.bins.bin64.alloc(),
-            ..=128 => self.bins.bin128.alloc(),
-            ..=256 => self.bins.bin256.alloc(),
-            ..=512 => self.bins.bin512.alloc(),
-            ..=1024 => self.bins.bin1024.alloc(),
-            ..=2048 => self.bins.bin2048.alloc(),
-            ..=4096 => self.bins.bin4096.alloc(),
-            ..=8192 => self.bins.bin8192.alloc(),
-            ..=16384 => self.bins.bin16384.alloc(),
-            ..=0x8000 => self.bins.bin32ki.alloc(),
-            ..=0x10000 => self.bins.bin64ki.alloc(),
-            _ => PAGE_ALLOCATOR.alloc(layout),
+            return core::ptr::null_mut();
+        }
+        #[cfg(feature = "std")]
+        let tag = match self.tags.reserve(layout.size()) {
+            Some(tag) => tag,
+            None => return core::ptr::null_mut(),
+        };
+        let (mut ptr, mut cache) = self.alloc_inner(layout);
+        let mut attempt = 0;
+        while ptr.is_null() && self.handle_oom(layout.size(), attempt) {
+            (ptr, cache) = self.alloc_inner(layout);
+            attempt += 1;
         }
+        #[cfg(feature = "std")]
+        self.tags.commit(tag, ptr, layout.size());
+        self.notify_alloc(ptr, layout, cache);
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let size = layout.pad_to_align().size();
-        match size {
-            ..=4 => self.bins.bin4.dealloc(ptr),
-            ..=8 => self.bins.bin8.dealloc(ptr),
-            ..=16 => self.bins.bin16.dealloc(ptr),
-            ..=32 => self.bins.bin32.dealloc(ptr),
-            ..=64 => self.bins.bin64.dealloc(ptr),
-            ..=128 => self.bins.bin128.dealloc(ptr),
-            ..=256 => self.bins.bin256.dealloc(ptr),
-            ..=512 => self.bins.bin512.dealloc(ptr),
-            ..=1024 => self.bins.bin1024.dealloc(ptr),
-            ..=2048 => self.bins.bin2048.dealloc(ptr),
-            ..=4096 => self.bins.bin4096.dealloc(ptr),
-            ..=8192 => self.bins.bin8192.dealloc(ptr),
-            ..=16384 => self.bins.bin16384.dealloc(ptr),
-            ..=0x8000 => self.bins.bin32ki.dealloc(ptr),
-            ..=0x10000 => self.bins.bin64ki.dealloc(ptr),
-            _ => PAGE_ALLOCATOR.dealloc(ptr, layout),
-        }
+        #[cfg(feature = "std")]
+        self.tags.release(ptr, layout.size());
+        let cache = self.dealloc_inner(ptr, layout);
+        self.notify_dealloc(ptr, layout, cache);
     }
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         if layout.align() > MAX_ALIGN {
-            return ptr::null_mut();
-        }
-        if layout.pad_to_align().size() > RSB_CHUNK_SIZE
-            && Layout::from_size_align_unchecked(new_size, layout.align())
-                .pad_to_align()
-                .size()
-                > RSB_CHUNK_SIZE
-        {
-            return PAGE_ALLOCATOR.realloc(ptr, layout, new_size);
-        }
-        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
-        if new_ptr != ptr {
-            core::ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
-            self.dealloc(ptr, layout);
+            return core::ptr::null_mut();
         }
+        #[cfg(feature = "std")]
+        if !self.tags.reserve_resize(ptr, layout.size(), new_size) {
+            return core::ptr::null_mut();
+        }
+        let mut new_ptr = self.realloc_untagged(ptr, layout, new_size);
+        let mut attempt = 0;
+        while new_ptr.is_null() && self.handle_oom(new_size, attempt) {
+            new_ptr = self.realloc_untagged(ptr, layout, new_size);
+            attempt += 1;
+        }
+        #[cfg(feature = "std")]
+        self.tags
+            .commit_resize(ptr, new_ptr, layout.size(), new_size);
         new_ptr
     }
 }
 
-#[derive(Default)]
-pub(crate) struct Bins {
-    pub(crate) bin4: Bin<Slot4>,
-    pub(crate) bin8: Bin<Slot8>,
-    pub(crate) bin16: Bin<Slot16>,
-    pub(crate) bin32: Bin<Slot32>,
-    pub(crate) bin64: Bin<Slot64>,
-    pub(crate) bin128: Bin<Slot128>,
-    pub(crate) bin256: Bin<Slot256>,
-    pub(crate) bin512: Bin<Slot512>,
-    pub(crate) bin1024: Bin<Slot1024>,
-    pub(crate) bin2048: Bin<Slot2048>,
-    pub(crate) bin4096: Bin<Slot4096>,
-    pub(crate) bin8192: Bin<Slot8192>,
-    pub(crate) bin16384: Bin<Slot16384>,
-    pub(crate) bin32ki: Bin<Slot32Ki>,
-    pub(crate) bin64ki: Bin<Slot64Ki>,
+impl<P: PageSource> RSBMalloc<P> {
+    /// Keeps the block where it is if the new size is in the same size
+    /// class. Otherwise it moves, with a single copy, between slots, between
+    /// a slot and a mapping, or by remapping.
+    unsafe fn realloc_untagged(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (
+            size_class(layout.pad_to_align().size()),
+            size_class(new_layout.pad_to_align().size()),
+        ) {
+            (None, None) => {
+                let new_ptr = self.realloc_large(ptr, layout, new_size);
+                self.notify_realloc(ptr, layout, new_ptr, new_size, None);
+                return new_ptr;
+            }
+            (Some(old_class), Some(new_class)) if old_class == new_class => {
+                let (cache, _) = self.current_bins();
+                self.notify_realloc(ptr, layout, ptr, new_size, Some(cache));
+                return ptr;
+            }
+            _ => {}
+        }
+        let (new_ptr, cache) = self.alloc_inner(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc_inner(ptr, layout);
+        }
+        self.notify_realloc(ptr, layout, new_ptr, new_size, cache);
+        new_ptr
+    }
 }
 
-impl Bins {
-    const fn new() -> Self {
-        Self {
-            bin4: Bin::new(),
-            bin8: Bin::new(),
-            bin16: Bin::new(),
-            bin32: Bin::new(),
-            bin64: Bin::new(),
-            bin128: Bin::new(),
-            bin256: Bin::new(),
-            bin512: Bin::new(),
-            bin1024: Bin::new(),
-            bin2048: Bin::new(),
-            bin4096: Bin::new(),
-            bin8192: Bin::new(),
-            bin16384: Bin::new(),
-            bin32ki: Bin::new(),
-            bin64ki: Bin::new(),
+impl<P: PageSource> RSBMalloc<P> {
+    /// Serves `layout` without notifying anyone, returning the index of the
+    /// cache it came from, or `None` for a large allocation
+    #[inline]
+    pub(crate) unsafe fn alloc_inner(&self, layout: Layout) -> (*mut u8, Option<usize>) {
+        match size_class(layout.pad_to_align().size()) {
+            Some(class) => {
+                let (cache, bins) = self.current_bins();
+                (bins.alloc(class, &self.pages, &self.budget), Some(cache))
+            }
+            None => (self.alloc_large(layout), None),
+        }
+    }
+
+    #[inline]
+    pub(crate) unsafe fn dealloc_inner(&self, ptr: *mut u8, layout: Layout) -> Option<usize> {
+        match size_class(layout.pad_to_align().size()) {
+            Some(class) => {
+                let (cache, bins) = self.current_bins();
+                bins.dealloc(class, ptr);
+                Some(cache)
+            }
+            None => {
+                self.dealloc_large(ptr, layout);
+                None
+            }
         }
     }
 }
 
-pub(crate) trait Slot {
-    /// Size is not always the size of the type
-    /// For example, a 4 byte size would be valid but the type would be
-    /// pointer-sized
-    const SIZE: usize;
-    unsafe fn buf(&mut self) -> *mut u8;
-    unsafe fn next(&self) -> Option<NonNull<Self>>;
-    unsafe fn set_next(&mut self, next: Option<NonNull<Self>>);
+pub(crate) const NUM_CLASSES: usize = 15;
+
+/// Index of the size class serving a padded `size`, smallest first, or
+/// `None` if it needs a large allocation
+#[inline]
+pub(crate) fn size_class(size: usize) -> Option<usize> {
+    if size > RSB_CHUNK_SIZE {
+        return None;
+    }
+    // Bits needed for `size - 1`, i.e. the log of the next power of two,
+    // counted from the 4-byte class
+    Some((usize::BITS - (size.max(4) - 1).leading_zeros()) as usize - 2)
 }
 
-macro_rules! slot {
-    ($name:ident, $len:literal, $align:literal) => {
-        slot_align!($name, $len, $align);
-    };
-    ($name:ident, $len:literal) => {
-        slot_align!($name, $len, $len);
-    };
+/// Largest allocation served by a size class
+pub(crate) const fn class_size(class: usize) -> usize {
+    4 << class
 }
 
-macro_rules! slot_align {
-    ($name:ident, $len:literal,$align:literal) => {
-        #[repr(align($align))]
-        pub(crate) union $name {
-            pub(crate) buf: [u8; $len],
-            pub(crate) next: Option<NonNull<$name>>,
+/// One bin per size class, smallest first
+pub(crate) struct Bins([Bin; NUM_CLASSES]);
+
+impl Bins {
+    const_fn! {
+        fn new() -> Self {
+            Self([
+                Bin::for_class(0),
+                Bin::for_class(1),
+                Bin::for_class(2),
+                Bin::for_class(3),
+                Bin::for_class(4),
+                Bin::for_class(5),
+                Bin::for_class(6),
+                Bin::for_class(7),
+                Bin::for_class(8),
+                Bin::for_class(9),
+                Bin::for_class(10),
+                Bin::for_class(11),
+                Bin::for_class(12),
+                Bin::for_class(13),
+                Bin::for_class(14),
+            ])
         }
+    }
 
-        impl Slot for $name {
-            const SIZE: usize = $len;
+    /// Every bin, smallest size class first
+    pub(crate) fn as_array(&self) -> &[Bin; NUM_CLASSES] {
+        &self.0
+    }
 
-            #[inline(always)]
-            unsafe fn buf(&mut self) -> *mut u8 {
-                &mut self.buf[..] as *mut [u8] as *mut u8
-            }
+    /// The bin serving `size`, or `None` if it needs a large allocation
+    pub(crate) fn for_size(&self, size: usize) -> Option<&Bin> {
+        size_class(size).map(|class| &self.0[class])
+    }
 
-            #[inline(always)]
-            unsafe fn next(&self) -> Option<NonNull<$name>> {
-                self.next
-            }
+    /// Allocates from the bin of a class from `size_class`
+    #[inline]
+    unsafe fn alloc(&self, class: usize, pages: &dyn PageSource, budget: &Budget) -> *mut u8 {
+        self.0[class].alloc(pages, budget)
+    }
 
-            #[inline(always)]
-            unsafe fn set_next(&mut self, next: Option<NonNull<$name>>) {
-                self.next = next;
-            }
-        }
-    };
+    #[inline]
+    unsafe fn dealloc(&self, class: usize, ptr: *mut u8) {
+        self.0[class].dealloc(ptr)
+    }
 }
 
-struct Slice {
-    ptr: *mut u8,
-    len: usize,
+impl Default for Bins {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-unsafe impl Send for Slice {}
+/// Snapshot of how many slots a bin has handed out
+#[derive(Clone, Copy, Default)]
+pub(crate) struct BinUsage {
+    pub(crate) chunks: usize,
+    /// Slots carved out of chunks, whether currently in use or free
+    pub(crate) carved: usize,
+    /// Slots sitting on the free list
+    pub(crate) free: usize,
+}
+
+impl BinUsage {
+    pub(crate) fn used(&self) -> usize {
+        self.carved.saturating_sub(self.free)
+    }
+}
 
-struct FreeList<S: Slot> {
-    ptr: *mut S,
+/// Bump pointer into a bin's current chunk, advanced with compare-exchange
+/// so carving a slot never takes a lock.
+///
+/// Chunks are aligned to their size, so the cursor alone says how much is
+/// left: a cursor on a chunk boundary, including null, has nothing left to
+/// carve. That's also where it ends up once a chunk is used up, unless the
+/// slot size doesn't divide the chunk and the tail is too short for a slot.
+struct Slice {
+    cursor: AtomicUsize,
 }
 
-unsafe impl<S: Slot> Send for FreeList<S> {}
+impl Slice {
+    const_fn! {
+        fn new() -> Self {
+            Self {
+                cursor: AtomicUsize::new(0),
+            }
+        }
+    }
 
-impl<S: Slot> FreeList<S> {
-    fn exists(&self) -> bool {
-        !self.ptr.is_null()
+    /// Carves `size` bytes off the current chunk, or returns null if it's used
+    /// up
+    fn carve(&self, size: usize) -> *mut u8 {
+        self.carve_many(size, 1).0
     }
-    const fn null() -> Self {
-        Self {
-            ptr: core::ptr::null_mut(),
+
+    /// Carves up to `count` consecutive pieces of `size` bytes, as many as
+    /// the current chunk has left, returning the first and how many there are
+    fn carve_many(&self, size: usize, count: usize) -> (*mut u8, usize) {
+        let mut cursor = self.cursor.load(Ordering::Acquire);
+        loop {
+            let left = match cursor % RSB_CHUNK_SIZE {
+                0 => 0,
+                carved => (RSB_CHUNK_SIZE - carved) / size,
+            };
+            if left == 0 {
+                return (core::ptr::null_mut(), 0);
+            }
+            let carved = min(count, left);
+            match self.cursor.compare_exchange_weak(
+                cursor,
+                cursor + carved * size,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return (cursor as *mut u8, carved),
+                Err(current) => cursor = current,
+            }
         }
     }
-    unsafe fn get_next(&self) -> Option<NonNull<S>> {
-        (*self.ptr).next()
+
+    /// Starts carving from a fresh chunk, `carved` bytes in
+    fn start(&self, chunk: *mut u8, carved: usize) {
+        self.cursor
+            .store(chunk as usize + carved, Ordering::Release);
     }
-    unsafe fn get_buf(&self) -> *mut u8 {
-        (*self.ptr).buf()
+
+    /// Stops carving until the cursor is restored, returning it
+    fn take(&self) -> usize {
+        self.cursor.swap(0, Ordering::AcqRel)
     }
-    fn option_nn(&self) -> Option<NonNull<S>> {
-        NonNull::new(self.ptr)
+
+    fn restore(&self, cursor: usize) {
+        self.cursor.store(cursor, Ordering::Release);
     }
-}
 
-impl<S: Slot> From<Option<NonNull<S>>> for FreeList<S> {
-    fn from(value: Option<NonNull<S>>) -> Self {
-        Self {
-            ptr: match value {
-                Some(nn) => nn.as_ptr(),
-                None => core::ptr::null_mut(),
-            },
-        }
+    fn load(&self) -> usize {
+        self.cursor.load(Ordering::Acquire)
     }
-}
-impl<S: Slot> From<*mut S> for FreeList<S> {
-    fn from(value: *mut S) -> Self {
-        Self { ptr: value }
+
+    /// The chunk a cursor points into and how many bytes of it are carved,
+    /// or `None` for a null cursor
+    fn current(cursor: usize) -> Option<(*mut u8, usize)> {
+        match cursor % RSB_CHUNK_SIZE {
+            _ if cursor == 0 => None,
+            0 => Some(((cursor - RSB_CHUNK_SIZE) as *mut u8, RSB_CHUNK_SIZE)),
+            carved => Some(((cursor - carved) as *mut u8, carved)),
+        }
     }
 }
 
-struct Bin<S: Slot> {
-    free_head: Mutex<FreeList<S>>,
-    page: Mutex<Slice>,
+/// Free-list allocator for slots of one size, carved from 64 KiB chunks.
+/// Free slots hold the link to the next one in their first word.
+pub(crate) struct Bin {
+    free_head: FreeList,
+    page: Slice,
+    /// Every chunk this bin has carved slots from, the current one last.
+    /// Also serializes starting new chunks.
+    chunks: sync::Mutex<ChunkList>,
+    /// Length of `chunks` and of the free list, kept in atomics so `usage`
+    /// takes no locks. The free count is raised before slots are pushed and
+    /// lowered after they're taken, so it never falls below the real one.
+    chunk_count: AtomicUsize,
+    free_count: AtomicUsize,
+    /// Largest allocation served
+    class_size: usize,
+    /// Bytes between consecutive slots. Chunks are aligned to their size, so
+    /// every slot is aligned to the largest power of two dividing this.
+    slot_size: usize,
 }
 
-impl<S: Slot> Default for Bin<S> {
-    fn default() -> Self {
-        Self {
-            free_head: Mutex::new(FreeList::null()),
-            page: Mutex::new(Slice {
-                ptr: core::ptr::null_mut(),
-                len: 0,
-            }),
+impl Bin {
+    const_fn! {
+        /// A bin for blocks of up to `class_size` bytes whose slots are aligned
+        /// to `align`, a power of two no bigger than a chunk. Slots are rounded
+        /// up to a word so they can hold a link.
+        pub(crate) fn new(class_size: usize, align: usize) -> Self {
+            let word = mem::size_of::<usize>();
+            let align = if align < word { word } else { align };
+            let slot_size = if class_size < word { word } else { class_size };
+            Self {
+                free_head: FreeList::new(),
+                page: Slice::new(),
+                chunks: sync::Mutex::new(ChunkList::new()),
+                chunk_count: AtomicUsize::new(0),
+                free_count: AtomicUsize::new(0),
+                class_size,
+                slot_size: (slot_size + align - 1) & !(align - 1),
+            }
         }
     }
-}
 
-slot!(Slot4, 0x4);
-slot!(Slot8, 0x8, 0x4);
-slot!(Slot16, 0x10);
-slot!(Slot32, 0x20);
-slot!(Slot64, 0x40);
-slot!(Slot128, 0x80);
-slot!(Slot256, 0x100);
-slot!(Slot512, 0x200);
-slot!(Slot1024, 0x400);
-slot!(Slot2048, 0x800);
-slot!(Slot4096, 0x1000);
-slot!(Slot8192, 0x2000, 0x1000);
-slot!(Slot16384, 0x4000, 0x1000);
-slot!(Slot32Ki, 0x8000, 0x1000);
-slot!(Slot64Ki, 0x10000, 0x1000);
-
-impl<S: Slot> Bin<S> {
-    fn add_one(&self) -> *mut S {
-        let slot_size = mem::size_of::<S>();
-        let mut page = self.page.lock();
-        if !page.ptr.is_null() {
-            if page.len >= slot_size {
-                let ret = page.ptr as *mut S;
-                unsafe {
-                    page.ptr = page.ptr.add(slot_size);
-                    page.len -= slot_size;
-                }
-                return ret;
-            }
+    const_fn! {
+        /// Slots of power-of-two classes are aligned to their size, up to
+        /// `MAX_ALIGN`
+        fn for_class(class: usize) -> Self {
+            let size = class_size(class);
+            Self::new(size, if size < MAX_ALIGN { size } else { MAX_ALIGN })
+        }
+    }
+
+    pub(crate) fn class_size(&self) -> usize {
+        self.class_size
+    }
+
+    pub(crate) fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    fn add_one(&self, pages: &dyn PageSource, budget: &Budget) -> *mut u8 {
+        let slot = self.page.carve(self.slot_size);
+        if !slot.is_null() {
+            return slot;
+        }
+        let mut chunks = self.chunks.lock();
+        // Another thread may have started a chunk while we waited
+        let slot = self.page.carve(self.slot_size);
+        if !slot.is_null() {
+            return slot;
         }
         unsafe {
-            let ptr = PAGE_ALLOCATOR.alloc(Layout::from_size_align_unchecked(
-                RSB_CHUNK_SIZE,
-                mem::align_of::<S>(),
-            ));
-            let ret = ptr as *mut S;
-            page.ptr = ptr.add(slot_size);
-            page.len = RSB_CHUNK_SIZE - slot_size;
-            ret
-        }
-    }
-
-    /// Allocates a pointer with size SIZE
-    unsafe fn alloc(&self) -> *mut u8 {
-        let mut free_head = self.free_head.lock();
-        if free_head.exists() {
-            let buf = free_head.get_buf();
-            (*free_head) = free_head.get_next().into();
-            buf
+            if !budget.try_charge(RSB_CHUNK_SIZE) {
+                return core::ptr::null_mut();
+            }
+            let ptr = pages.alloc_chunk(self.slot_size);
+            if ptr.is_null() {
+                budget.refund(RSB_CHUNK_SIZE);
+                return core::ptr::null_mut();
+            }
+            if !chunks.push(ptr, page_allocator::CHUNK_LAYOUT) {
+                pages.dealloc_chunk(ptr);
+                budget.refund(RSB_CHUNK_SIZE);
+                return core::ptr::null_mut();
+            }
+            self.chunk_count.fetch_add(1, Ordering::Relaxed);
+            self.page.start(ptr, self.slot_size);
+            ptr
+        }
+    }
+
+    /// Allocates a slot, or returns null if no chunk could be mapped
+    pub(crate) unsafe fn alloc(&self, pages: &dyn PageSource, budget: &Budget) -> *mut u8 {
+        let slot = self.free_head.pop();
+        if slot.is_null() {
+            self.add_one(pages, budget)
         } else {
-            drop(free_head);
-            (*self.add_one()).buf()
+            self.free_count.fetch_sub(1, Ordering::Relaxed);
+            slot
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8) {
-        let slot_ptr = ptr as *mut S;
-        let mut free_head = self.free_head.lock();
-        (*slot_ptr).set_next((*free_head).option_nn());
-        (*free_head) = FreeList::from(slot_ptr);
+    pub(crate) unsafe fn dealloc(&self, ptr: *mut u8) {
+        self.free_count.fetch_add(1, Ordering::Relaxed);
+        self.free_head.push(ptr);
     }
 
-    const fn new() -> Self {
-        Self {
-            free_head: Mutex::new(FreeList::null()),
-            page: Mutex::new(Slice {
-                ptr: core::ptr::null_mut(),
-                len: 0,
-            }),
+    /// Fills `out` from the free list, then from the current chunk, starting
+    /// new chunks as needed. Returns how many slots were allocated.
+    pub(crate) unsafe fn alloc_many(
+        &self,
+        pages: &dyn PageSource,
+        budget: &Budget,
+        out: &mut [*mut u8],
+    ) -> usize {
+        let mut filled = self.free_head.pop_many(out);
+        self.free_count.fetch_sub(filled, Ordering::Relaxed);
+        while filled < out.len() {
+            let (first, carved) = self.page.carve_many(self.slot_size, out.len() - filled);
+            if carved == 0 {
+                let slot = self.add_one(pages, budget);
+                if slot.is_null() {
+                    break;
+                }
+                out[filled] = slot;
+                filled += 1;
+                continue;
+            }
+            for (i, ptr) in out[filled..filled + carved].iter_mut().enumerate() {
+                *ptr = first.add(i * self.slot_size);
+            }
+            filled += carved;
+        }
+        filled
+    }
+
+    /// Links `ptrs` together and pushes them in one go
+    pub(crate) unsafe fn dealloc_many(&self, ptrs: &[*mut u8]) {
+        let (first, last) = match (ptrs.first(), ptrs.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return,
+        };
+        for pair in ptrs.windows(2) {
+            free_list::set_next(pair[0], pair[1]);
         }
+        self.free_count.fetch_add(ptrs.len(), Ordering::Relaxed);
+        self.free_head.push_chain(first, last);
+    }
+
+    /// Reads the counters without locking, so it's safe in a signal handler.
+    /// Under concurrent use the numbers may be slightly out of step.
+    pub(crate) fn usage(&self) -> BinUsage {
+        let chunks = self.chunk_count.load(Ordering::Relaxed);
+        let per_chunk = RSB_CHUNK_SIZE / self.slot_size;
+        let carved = match Slice::current(self.page.load()) {
+            _ if chunks == 0 => 0,
+            Some((_, carved)) => (chunks - 1) * per_chunk + carved / self.slot_size,
+            None => chunks * per_chunk,
+        };
+        BinUsage {
+            chunks,
+            carved,
+            free: self.free_count.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Changes whenever slots are allocated, freed or carved
+    #[cfg(feature = "std")]
+    pub(crate) fn fingerprint(&self) -> [usize; 3] {
+        [
+            self.free_head.version(),
+            self.page.load(),
+            self.chunk_count.load(Ordering::Relaxed),
+        ]
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod test {
     extern crate alloc;
     extern crate std;
     use core::{
         alloc::{GlobalAlloc, Layout},
         hint::black_box,
-        mem,
+        mem, ptr,
     };
 
-    use std::{panic::catch_unwind, vec, vec::Vec};
+    use std::{panic::catch_unwind, string::String, vec, vec::Vec};
 
     use std::thread;
 
     use alloc::collections::BTreeMap;
 
-    use crate::*;
+    use crate::{page_allocator::PAGE_ALLOCATOR, trim::TrimMode, *};
+    use proptest::prelude::*;
+
+    /// Rounds of the threaded stress tests, fewer under Miri, which runs
+    /// them orders of magnitude slower
+    const ROUNDS: usize = if cfg!(miri) { 20 } else { 2000 };
+
+    /// `write_bytes`, with the block's first word stored atomically. A pop
+    /// that lost a race may still read a slot's link after the slot's been
+    /// handed out, and Miri reports that read against a plain write. Blocks
+    /// always span at least that word.
+    unsafe fn fill(ptr: *mut u8, byte: u8, len: usize) {
+        let word = mem::size_of::<usize>();
+        (*(ptr as *const core::sync::atomic::AtomicUsize)).store(
+            usize::MAX / 0xff * byte as usize,
+            core::sync::atomic::Ordering::Relaxed,
+        );
+        if len > word {
+            ptr.add(word).write_bytes(byte, len - word);
+        }
+    }
 
     #[repr(align(512))]
     struct Big {
         _contents: [u8; 512],
     }
 
+    #[allow(clippy::needless_range_loop)]
     unsafe fn test_allocator<A: GlobalAlloc>(allocator: A) {
         std::println!("Allocating 100 i32s");
         let mut pointer = allocator.alloc(Layout::new::<[i32; 100]>());
@@ -393,7 +736,7 @@ mod test {
         // Free the memory
         allocator.dealloc(ptr, Layout::new::<u64>());
 
-        let mut ptr_buf: [*mut u8; 256] = [0 as *mut u8; 256];
+        let mut ptr_buf: [*mut u8; 256] = [ptr::null_mut(); 256];
 
         for i in 0..256 {
             let pointer = allocator.alloc(Layout::new::<Big>());
@@ -425,12 +768,76 @@ mod test {
         }
     }
 
+    #[test]
+    fn reserve_pages() {
+        use page_allocator::{Protection, PAGE_ALLOCATOR, PAGE_SIZE};
+
+        let page_size = *PAGE_SIZE;
+        let region = PAGE_ALLOCATOR.reserve(16 * page_size);
+        assert!(!region.is_null());
+        unsafe {
+            // Grow a buffer a page at a time without it ever moving
+            for page in 0..4 {
+                let ptr = region.add(page * page_size);
+                assert!(PAGE_ALLOCATOR.commit(ptr, page_size));
+                assert_eq!(*ptr, 0);
+                ptr.write_bytes(page as u8 + 1, page_size);
+            }
+            for page in 0..4 {
+                assert_eq!(*region.add(page * page_size + 7), page as u8 + 1);
+            }
+
+            assert!(PAGE_ALLOCATOR.protect(region, page_size, Protection::Read));
+            assert_eq!(*region, 1);
+            assert!(PAGE_ALLOCATOR.protect(region, page_size, Protection::ReadWrite));
+            *region = 9;
+
+            assert!(PAGE_ALLOCATOR.decommit(region.add(page_size), 2 * page_size));
+            assert!(PAGE_ALLOCATOR.commit(region.add(page_size), 2 * page_size));
+            assert_eq!(*region.add(page_size), 0);
+            assert_eq!(*region, 9);
+            assert_eq!(*region.add(3 * page_size), 4);
+
+            assert!(PAGE_ALLOCATOR.release(region, 16 * page_size));
+        }
+    }
+
     #[test]
     fn align() {
-        assert_eq!(mem::align_of::<Slot4>(), 8);
-        assert_eq!(mem::align_of::<Slot16>(), 16);
-        assert_eq!(mem::align_of::<Slot256>(), 256);
-        assert_eq!(mem::align_of::<Slot1024>(), 1024);
+        let bins = Bins::new();
+        let budget = Budget::new();
+        for (class, bin) in bins.as_array().iter().enumerate() {
+            let align = class_size(class).clamp(mem::size_of::<usize>(), MAX_ALIGN);
+            assert_eq!(bin.slot_size() % align, 0);
+            unsafe {
+                let a = bin.alloc(&PAGE_ALLOCATOR, &budget);
+                let b = bin.alloc(&PAGE_ALLOCATOR, &budget);
+                assert_eq!(a as usize % align, 0);
+                assert_eq!(b as usize % align, 0);
+                bin.dealloc(a);
+                bin.dealloc(b);
+            }
+            bin.trim(
+                &mut 0,
+                &PAGE_ALLOCATOR,
+                &budget,
+                TrimMode::Eager,
+                &|visit| visit(&bin.free_head),
+            );
+        }
+        assert_eq!(Bin::new(24, 8).slot_size(), 24);
+        assert_eq!(Bin::new(2, 2).slot_size(), mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn size_classes() {
+        for size in 0..=RSB_CHUNK_SIZE {
+            let class = size_class(size).unwrap();
+            let class_size = Bins::new().as_array()[class].class_size();
+            assert!(size <= class_size && (class == 0 || size > class_size / 2));
+        }
+        assert_eq!(size_class(RSB_CHUNK_SIZE + 1), None);
+        assert_eq!(size_class(usize::MAX), None);
     }
 
     #[test]
@@ -438,10 +845,1125 @@ mod test {
         unsafe { test_allocator(RSBMalloc::new()) };
     }
 
+    /// One step of a randomized run. Indices pick a live block, modulo how
+    /// many there are.
+    #[derive(Clone, Debug)]
+    enum Op {
+        Alloc(Layout),
+        Realloc(usize, usize),
+        Dealloc(usize),
+    }
+
+    /// Most ops in one property test case. Every byte allocated is filled
+    /// and checked, which is slow under Miri.
+    const MAX_OPS: usize = if cfg!(miri) { 20 } else { 300 };
+
+    /// Sizes spread evenly over every size class and well into large
+    /// allocations
+    fn any_size() -> impl Strategy<Value = usize> {
+        (0..19u32, any::<usize>()).prop_map(|(bits, n)| n % (1 << bits) + 1)
+    }
+
+    /// Alignments up to `MAX_ALIGN`, the largest supported
+    fn any_op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            2 => (any_size(), 0..=MAX_ALIGN.trailing_zeros()).prop_map(|(size, shift)| {
+                Op::Alloc(Layout::from_size_align(size, 1 << shift).unwrap())
+            }),
+            1 => (any::<usize>(), any_size()).prop_map(|(i, size)| Op::Realloc(i, size)),
+            1 => any::<usize>().prop_map(Op::Dealloc),
+        ]
+    }
+
+    /// A live block from `run_ops` and the byte it's filled with
+    struct Filled {
+        ptr: *mut u8,
+        layout: Layout,
+        byte: u8,
+    }
+
+    unsafe impl Send for Filled {}
+
+    impl Filled {
+        unsafe fn check(&self, len: usize) {
+            let contents = core::slice::from_raw_parts(self.ptr, len);
+            // One slice comparison instead of a loop, which Miri would step
+            // through byte by byte
+            assert!(
+                contents == vec![self.byte; len].as_slice(),
+                "block at {:p} was corrupted",
+                self.ptr
+            );
+        }
+    }
+
+    /// Records a block's address range, checking it doesn't overlap another
+    fn claim(ranges: &mut BTreeMap<usize, usize>, ptr: *mut u8, size: usize) {
+        let (start, end) = (ptr as usize, ptr as usize + size);
+        if let Some((_, &prev_end)) = ranges.range(..=start).next_back() {
+            assert!(
+                prev_end <= start,
+                "block at {ptr:p} overlaps the one before"
+            );
+        }
+        if let Some((&next_start, _)) = ranges.range(start..).next() {
+            assert!(end <= next_start, "block at {ptr:p} overlaps the one after");
+        }
+        ranges.insert(start, end);
+    }
+
+    /// Runs `ops` against `allocator`, filling every block with a byte of its
+    /// own and checking it's intact before the block is resized or freed.
+    /// Returns the blocks still live, unchecked.
+    unsafe fn run_ops(allocator: &dyn GlobalAlloc, ops: &[Op], first_byte: u8) -> Vec<Filled> {
+        let mut live: Vec<Filled> = Vec::new();
+        let mut ranges = BTreeMap::new();
+        let mut byte = first_byte;
+        for op in ops {
+            match *op {
+                Op::Alloc(layout) => {
+                    let ptr = allocator.alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % layout.align(), 0);
+                    claim(&mut ranges, ptr, layout.size());
+                    byte = byte.wrapping_add(1);
+                    fill(ptr, byte, layout.size());
+                    live.push(Filled { ptr, layout, byte });
+                }
+                Op::Realloc(i, new_size) if !live.is_empty() => {
+                    let len = live.len();
+                    let block = &mut live[i % len];
+                    block.check(block.layout.size());
+                    ranges.remove(&(block.ptr as usize));
+                    let ptr = allocator.realloc(block.ptr, block.layout, new_size);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % block.layout.align(), 0);
+                    let kept = min(block.layout.size(), new_size);
+                    block.ptr = ptr;
+                    block.layout = Layout::from_size_align(new_size, block.layout.align()).unwrap();
+                    block.check(kept);
+                    claim(&mut ranges, ptr, new_size);
+                    fill(ptr, block.byte, new_size);
+                }
+                Op::Dealloc(i) if !live.is_empty() => {
+                    let block = live.swap_remove(i % live.len());
+                    block.check(block.layout.size());
+                    ranges.remove(&(block.ptr as usize));
+                    allocator.dealloc(block.ptr, block.layout);
+                }
+                _ => {}
+            }
+        }
+        live
+    }
+
+    proptest! {
+        // Set PROPTEST_CASES for a longer soak
+        #![proptest_config(ProptestConfig {
+            cases: if cfg!(miri) { 4 } else { 32 },
+            // Failures are saved beside the source, which Miri's isolation hides
+            failure_persistence: if cfg!(miri) {
+                None
+            } else {
+                ProptestConfig::default().failure_persistence
+            },
+            ..ProptestConfig::default()
+        })]
+
+        #[test]
+        fn random_ops(ops in prop::collection::vec(any_op(), 1..MAX_OPS)) {
+            let allocator = RSBMalloc::new();
+            unsafe {
+                for block in run_ops(&allocator, &ops, 0) {
+                    block.check(block.layout.size());
+                    allocator.dealloc(block.ptr, block.layout);
+                }
+            }
+            prop_assert_eq!(allocator.leaks().blocks(), 0);
+        }
+
+        #[test]
+        fn random_ops_threaded(
+            threads in prop::collection::vec(
+                prop::collection::vec(any_op(), 1..MAX_OPS * 2 / 3),
+                2..8,
+            )
+        ) {
+            static THREADED: RSBMalloc = RSBMalloc::new();
+            let handles: Vec<_> = threads
+                .into_iter()
+                .enumerate()
+                .map(|(i, ops)| {
+                    thread::spawn(move || unsafe { run_ops(&THREADED, &ops, (i * 32) as u8) })
+                })
+                .collect();
+            // Whatever's left is freed here, away from the thread that
+            // allocated it
+            for handle in handles {
+                for block in handle.join().unwrap() {
+                    unsafe {
+                        block.check(block.layout.size());
+                        THREADED.dealloc(block.ptr, block.layout);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn random_page_ops(ops in prop::collection::vec(any_op(), 1..MAX_OPS / 3)) {
+            use page_allocator::PAGE_ALLOCATOR;
+
+            unsafe {
+                for block in run_ops(&PAGE_ALLOCATOR, &ops, 0) {
+                    block.check(block.layout.size());
+                    PAGE_ALLOCATOR.dealloc(block.ptr, block.layout);
+                }
+            }
+        }
+    }
+
     #[test]
+    fn leak_summary() {
+        let allocator = RSBMalloc::new();
+        let small = Layout::new::<[u8; 24]>();
+        let large = Layout::from_size_align(100_000, 8).unwrap();
+        unsafe {
+            let a = allocator.alloc(small);
+            let b = allocator.alloc(small);
+            let c = allocator.alloc(small);
+            let big = allocator.alloc(large);
+            allocator.dealloc(b, small);
+
+            let summary = allocator.leaks();
+            assert_eq!(
+                summary.classes[3],
+                ClassLeaks {
+                    size: 32,
+                    blocks: 2,
+                    bytes: 64
+                }
+            );
+            assert_eq!(summary.large.blocks, 1);
+            assert_eq!(summary.large.bytes, 100_000);
+
+            let mut report = String::new();
+            allocator.report_leaks(&mut report).unwrap();
+            assert!(report.contains("large"));
+
+            allocator.dealloc(a, small);
+            allocator.dealloc(c, small);
+            allocator.dealloc(big, large);
+        }
+        assert_eq!(allocator.leaks().blocks(), 0);
+    }
+
+    #[test]
+    fn walk_blocks() {
+        let allocator = RSBMalloc::new();
+        let small = Layout::new::<[u8; 24]>();
+        let large = Layout::from_size_align(100_000, 8).unwrap();
+        unsafe {
+            let a = allocator.alloc(small);
+            let b = allocator.alloc(small);
+            let c = allocator.alloc(small);
+            let big = allocator.alloc(large);
+            allocator.dealloc(b, small);
+
+            let mut blocks = Vec::new();
+            allocator.walk(|block| blocks.push(block));
+            let state_of = |ptr| blocks.iter().find(|block| block.ptr == ptr).unwrap().state;
+            assert_eq!(blocks.len(), 4);
+            assert_eq!(state_of(a), BlockState::Used);
+            assert_eq!(state_of(b), BlockState::Free);
+            assert_eq!(state_of(c), BlockState::Used);
+            assert_eq!(state_of(big), BlockState::Used);
+            assert!(blocks
+                .iter()
+                .all(|block| block.class == Some(32) || block.ptr == big));
+
+            allocator.dealloc(a, small);
+            allocator.dealloc(c, small);
+            allocator.dealloc(big, large);
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let allocator = RSBMalloc::new();
+        let small = Layout::new::<[u8; 24]>();
+        unsafe {
+            let a = allocator.alloc(small);
+            let b = allocator.alloc(small);
+            allocator.dealloc(b, small);
+
+            let stats = allocator.stats();
+            assert_eq!(stats.classes[3].chunks, 1);
+            assert_eq!(stats.classes[3].used, 1);
+            assert_eq!(stats.classes[3].free, 1);
+            assert_eq!(stats.mapped_bytes(), RSB_CHUNK_SIZE);
+            assert_eq!(stats.used_bytes(), 32);
+
+            let mut json = String::new();
+            allocator.dump_stats(&mut json, StatsFormat::Json).unwrap();
+            assert!(json.starts_with("{\"totals\":{\"classes\":["));
+            assert!(json.ends_with("}]}\n"));
+
+            allocator.dealloc(a, small);
+        }
+    }
+
+    #[test]
+    fn bin_usage() {
+        // 24-byte slots leave 16 bytes unused at the end of every chunk
+        let bin = Bin::new(24, 8);
+        let budget = Budget::new();
+        let per_chunk = RSB_CHUNK_SIZE / 24;
+        let slots: Vec<_> = (0..2 * per_chunk + 1)
+            .map(|_| unsafe { bin.alloc(&PAGE_ALLOCATOR, &budget) })
+            .collect();
+        let usage = bin.usage();
+        assert_eq!(usage.chunks, 3);
+        assert_eq!(usage.carved, slots.len());
+        assert_eq!(usage.used(), slots.len());
+        for &slot in &slots {
+            unsafe { bin.dealloc(slot) };
+        }
+        assert_eq!(bin.usage().free, slots.len());
+        assert_eq!(bin.usage().used(), 0);
+        bin.trim(
+            &mut 0,
+            &PAGE_ALLOCATOR,
+            &budget,
+            TrimMode::Eager,
+            &|visit| visit(&bin.free_head),
+        );
+        assert_eq!(bin.usage().chunks, 0);
+        assert_eq!(bin.usage().free, 0);
+    }
+
+    #[test]
+    fn hooks() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static HOOKED: RSBMalloc = RSBMalloc::new();
+
+        struct Counter {
+            allocs: AtomicUsize,
+            deallocs: AtomicUsize,
+            reallocs: AtomicUsize,
+        }
+
+        impl AllocHooks for Counter {
+            fn on_alloc(&self, event: AllocEvent) {
+                self.allocs.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(event.layout.size(), 24);
+                // Not reported, since we're already inside a hook
+                unsafe {
+                    let ptr = HOOKED.alloc(event.layout);
+                    HOOKED.dealloc(ptr, event.layout);
+                }
+            }
+            fn on_dealloc(&self, _event: AllocEvent) {
+                self.deallocs.fetch_add(1, Ordering::Relaxed);
+            }
+            fn on_realloc(&self, event: ReallocEvent) {
+                self.reallocs.fetch_add(1, Ordering::Relaxed);
+                assert_eq!(event.new_size, 100);
+                assert!(event.cache.is_some());
+            }
+        }
+
+        static COUNTER: Counter = Counter {
+            allocs: AtomicUsize::new(0),
+            deallocs: AtomicUsize::new(0),
+            reallocs: AtomicUsize::new(0),
+        };
+
+        assert!(HOOKED.set_hooks(&COUNTER));
+        assert!(!HOOKED.set_hooks(&COUNTER));
+        let layout = Layout::new::<[u8; 24]>();
+        unsafe {
+            let ptr = HOOKED.alloc(layout);
+            let ptr = HOOKED.realloc(ptr, layout, 100);
+            HOOKED.dealloc(ptr, Layout::from_size_align(100, 1).unwrap());
+        }
+        assert_eq!(COUNTER.allocs.load(Ordering::Relaxed), 1);
+        assert_eq!(COUNTER.reallocs.load(Ordering::Relaxed), 1);
+        assert_eq!(COUNTER.deallocs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn tags() {
+        const QUERY_CACHE: Tag = Tag::new(1);
+
+        let allocator = RSBMalloc::new();
+        let layout = Layout::new::<[u8; 100]>();
+        unsafe {
+            let a = with_tag(QUERY_CACHE, || allocator.alloc(layout));
+            let untagged = allocator.alloc(layout);
+            assert_eq!(allocator.tag_usage(QUERY_CACHE), 100);
+
+            allocator.set_tag_limit(QUERY_CACHE, Some(150));
+            assert!(with_tag(QUERY_CACHE, || allocator.alloc(layout)).is_null());
+            assert!(allocator.realloc(a, layout, 200).is_null());
+            assert_eq!(allocator.tag_usage(QUERY_CACHE), 100);
+
+            let a = allocator.realloc(a, layout, 50);
+            assert_eq!(allocator.tag_usage(QUERY_CACHE), 50);
+            allocator.dealloc(a, Layout::new::<[u8; 50]>());
+            allocator.dealloc(untagged, layout);
+            assert_eq!(allocator.tag_usage(QUERY_CACHE), 0);
+        }
+    }
+
+    #[test]
+    fn memory_limit() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static LIMITED: RSBMalloc = RSBMalloc::new();
+        static OOM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        fn lift_limit(_size: usize) -> bool {
+            OOM_CALLS.fetch_add(1, Ordering::Relaxed);
+            LIMITED.set_memory_limit(None);
+            true
+        }
+
+        fn keep_retrying(_size: usize) -> bool {
+            OOM_CALLS.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+
+        LIMITED.set_memory_limit(Some(RSB_CHUNK_SIZE));
+        let small = Layout::new::<[u8; 24]>();
+        let large = Layout::from_size_align(100_000, 8).unwrap();
+        unsafe {
+            let a = LIMITED.alloc(small);
+            assert!(!a.is_null());
+            assert_eq!(LIMITED.mapped_bytes(), RSB_CHUNK_SIZE);
+            assert!(LIMITED.alloc(large).is_null());
+            assert_eq!(LIMITED.mapped_bytes(), RSB_CHUNK_SIZE);
+
+            LIMITED.set_oom_handler(Some(lift_limit));
+            let big = LIMITED.alloc(large);
+            assert!(!big.is_null());
+            assert_eq!(OOM_CALLS.load(Ordering::Relaxed), 1);
+            assert_eq!(LIMITED.memory_limit(), None);
+
+            LIMITED.dealloc(big, large);
+            LIMITED.dealloc(a, small);
+
+            // A handler that never gives up only gets so many retries
+            LIMITED.set_memory_limit(Some(RSB_CHUNK_SIZE));
+            LIMITED.set_oom_handler(Some(keep_retrying));
+            OOM_CALLS.store(0, Ordering::Relaxed);
+            assert!(LIMITED.alloc(large).is_null());
+            assert_eq!(OOM_CALLS.load(Ordering::Relaxed), MAX_OOM_RETRIES);
+            let a = LIMITED.alloc(small);
+            assert!(LIMITED.realloc(a, small, large.size()).is_null());
+            assert_eq!(OOM_CALLS.load(Ordering::Relaxed), 2 * MAX_OOM_RETRIES);
+            LIMITED.dealloc(a, small);
+            LIMITED.set_oom_handler(None);
+        }
+        assert_eq!(LIMITED.mapped_bytes(), RSB_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn trim() {
+        let allocator = RSBMalloc::new();
+        let small = Layout::new::<[u8; 24]>();
+        let blocks: Vec<*mut u8> = (0..3000)
+            .map(|_| unsafe { allocator.alloc(small) })
+            .collect();
+        assert_eq!(allocator.mapped_bytes(), 2 * RSB_CHUNK_SIZE);
+        for &block in &blocks {
+            unsafe { allocator.dealloc(block, small) };
+        }
+
+        assert_eq!(allocator.trim(RSB_CHUNK_SIZE), RSB_CHUNK_SIZE);
+        assert_eq!(allocator.mapped_bytes(), RSB_CHUNK_SIZE);
+        assert_eq!(allocator.trim(0), RSB_CHUNK_SIZE);
+        assert_eq!(allocator.mapped_bytes(), 0);
+        assert_eq!(allocator.stats().classes[3].chunks, 0);
+
+        unsafe {
+            let a = allocator.alloc(small);
+            assert!(!a.is_null());
+            assert_eq!(allocator.stats().classes[3].used, 1);
+            allocator.dealloc(a, small);
+        }
+        assert_eq!(allocator.trim(0), RSB_CHUNK_SIZE);
+
+        // A free 16 KiB slot in a chunk that's still in use only gets its
+        // pages after the first purged
+        let medium = Layout::new::<[u8; 0x4000]>();
+        let blocks: Vec<*mut u8> = (0..5).map(|_| unsafe { allocator.alloc(medium) }).collect();
+        unsafe {
+            blocks[0].write_bytes(1, 0x4000);
+            allocator.dealloc(blocks[0], medium);
+        }
+        let page_size = *page_allocator::PAGE_SIZE;
+        assert_eq!(allocator.trim(0), 0x4000 - page_size);
+        unsafe {
+            let a = allocator.alloc(medium);
+            assert_eq!(a, blocks[0]);
+            assert_eq!(*a.add(page_size), 0);
+            allocator.dealloc(a, medium);
+            for &block in &blocks[1..] {
+                allocator.dealloc(block, medium);
+            }
+        }
+    }
+
+    /// Runs pairs of threads that free each other's blocks, so slots carved
+    /// in one cache are freed into another and then back, while `meanwhile`
+    /// runs on this thread until they're done. Each thread sleeps for
+    /// `pause` between rounds.
+    fn free_across_caches(
+        allocator: &'static RSBMalloc,
+        pause: std::time::Duration,
+        meanwhile: impl Fn(),
+    ) {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{mpsc, Arc};
+
+        const THREADS: usize = 8;
+
+        let small = Layout::new::<[u8; 24]>();
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..THREADS).map(|_| mpsc::channel()).unzip();
+        let running = Arc::new(AtomicUsize::new(THREADS));
+        let threads: Vec<_> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, received)| {
+                let partner = senders[i ^ 1].clone();
+                let running = running.clone();
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS / 4 {
+                        let blocks: Vec<usize> = (0..64)
+                            .map(|_| unsafe {
+                                let block = allocator.alloc(small);
+                                assert!(!block.is_null());
+                                fill(block, i as u8, 24);
+                                block as usize
+                            })
+                            .collect();
+                        partner.send(blocks).unwrap();
+                        let theirs: Vec<usize> = received.recv().unwrap();
+                        for block in theirs {
+                            let block = block as *mut u8;
+                            assert!(unsafe { core::slice::from_raw_parts(block, 24) }
+                                .iter()
+                                .all(|&byte| byte == (i ^ 1) as u8));
+                            unsafe { allocator.dealloc(block, small) };
+                        }
+                        thread::sleep(pause);
+                    }
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        while running.load(Ordering::SeqCst) > 0 {
+            meanwhile();
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn trim_with_cross_cache_frees() {
+        static TRIMMED: RSBMalloc = RSBMalloc::new();
+
+        free_across_caches(&TRIMMED, Default::default(), || {
+            TRIMMED.trim(0);
+        });
+        assert_eq!(TRIMMED.stats().classes[3].used, 0);
+    }
+
+    #[test]
+    fn page_source() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use page_allocator::PageSource;
+
+        /// Pages from `PAGE_ALLOCATOR`, counting how many bytes are out
+        #[derive(Default)]
+        struct Counting(AtomicUsize);
+
+        unsafe impl PageSource for Counting {
+            unsafe fn alloc_pages(&self, layout: Layout) -> *mut u8 {
+                self.0.fetch_add(layout.size(), Ordering::Relaxed);
+                PAGE_ALLOCATOR.alloc(layout)
+            }
+
+            unsafe fn dealloc_pages(&self, ptr: *mut u8, layout: Layout) {
+                self.0.fetch_sub(layout.size(), Ordering::Relaxed);
+                PAGE_ALLOCATOR.dealloc(ptr, layout)
+            }
+        }
+
+        let allocator = RSBMalloc::with_page_source(Counting::default());
+        let out = || allocator.pages.0.load(Ordering::Relaxed);
+        let small = Layout::new::<[u8; 24]>();
+        let large = Layout::from_size_align(100_000, 8).unwrap();
+        unsafe {
+            let a = allocator.alloc(small);
+            assert_eq!(out(), RSB_CHUNK_SIZE);
+            assert_eq!(allocator.usable_size(a), Some(32));
+
+            let b = allocator.alloc(large);
+            assert_eq!(out(), RSB_CHUNK_SIZE + 100_000);
+            b.write_bytes(7, 100_000);
+            let b = allocator.realloc(b, large, 300_000);
+            assert_eq!(out(), RSB_CHUNK_SIZE + 300_000);
+            assert_eq!(*b.add(99_999), 7);
+
+            allocator.dealloc(a, small);
+            allocator.dealloc(b, Layout::from_size_align(300_000, 8).unwrap());
+        }
+        assert_eq!(out(), RSB_CHUNK_SIZE);
+        assert_eq!(allocator.trim(0), RSB_CHUNK_SIZE);
+        assert_eq!(out(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "static_region")]
+    fn static_region() {
+        use page_allocator::StaticRegion;
+
+        let region = vec![0xaa_u8; 64 * RSB_CHUNK_SIZE].leak();
+        let (start, end) = (
+            region.as_ptr() as usize,
+            region.as_ptr() as usize + region.len(),
+        );
+        let pages = StaticRegion::new();
+        assert!(!pages.init(&mut []));
+        assert!(pages.init(region));
+        assert!(!pages.init(vec![0; RSB_CHUNK_SIZE].leak()));
+        let allocator = RSBMalloc::with_page_source(pages);
+
+        let small = Layout::new::<[u8; 24]>();
+        let large = Layout::from_size_align(200_000, 8).unwrap();
+        unsafe {
+            let blocks: Vec<*mut u8> = (0..5000).map(|_| allocator.alloc(small)).collect();
+            for &block in &blocks {
+                assert!((start..end).contains(&(block as usize)));
+                block.write_bytes(1, 24);
+            }
+            let a = allocator.alloc(large);
+            assert!((start..end).contains(&(a as usize)));
+            assert!(core::slice::from_raw_parts(a, 200_000)
+                .iter()
+                .all(|&b| b == 0));
+            a.write_bytes(2, 200_000);
+            let a = allocator.realloc(a, large, 400_000);
+            assert_eq!(*a.add(199_999), 2);
+            assert!(allocator
+                .alloc(Layout::from_size_align(64 * RSB_CHUNK_SIZE, 8).unwrap())
+                .is_null());
+
+            allocator.dealloc(a, Layout::from_size_align(400_000, 8).unwrap());
+            for &block in &blocks {
+                allocator.dealloc(block, small);
+            }
+        }
+        allocator.trim(0);
+        assert_eq!(allocator.mapped_bytes(), 0);
+        // Everything was given back, so most of the region is one run again
+        let most = Layout::from_size_align(48 * RSB_CHUNK_SIZE, 8).unwrap();
+        unsafe {
+            let a = allocator.alloc(most);
+            assert!(!a.is_null());
+            allocator.dealloc(a, most);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "std"))]
+    #[cfg_attr(miri, ignore)]
+    fn shared_heap() {
+        use std::sync::Arc;
+        use sync::atomic::Ordering;
+
+        let heap = Arc::new(RSBSharedHeap::create(16 * RSB_CHUNK_SIZE).unwrap());
+        // A second mapping stands in for another process
+        let other = Arc::new(unsafe { RSBSharedHeap::from_fd(libc::dup(heap.fd())) }.unwrap());
+        assert_ne!(heap.at_offset(0), other.at_offset(0));
+        let word = Layout::new::<u64>();
+        unsafe {
+            let a = heap.alloc(word);
+            (a as *mut u64).write(42);
+            heap.root().store(heap.offset_of(a), Ordering::Release);
+            let b = other.at_offset(other.root().load(Ordering::Acquire));
+            assert_eq!(*(b as *mut u64), 42);
+            other.dealloc(b, word);
+            assert_eq!(heap.alloc(word), a);
+            heap.dealloc(a, word);
+        }
+
+        let block = Layout::new::<[u8; 48]>();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let (mine, theirs) = match i % 2 {
+                    0 => (heap.clone(), other.clone()),
+                    _ => (other.clone(), heap.clone()),
+                };
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS / 4 {
+                        let held: Vec<*mut u8> =
+                            (0..16).map(|_| unsafe { mine.alloc(block) }).collect();
+                        for &ptr in &held {
+                            assert!(!ptr.is_null());
+                            unsafe { fill(ptr, i as u8, 48) };
+                        }
+                        for &ptr in &held {
+                            let ptr = theirs.at_offset(mine.offset_of(ptr));
+                            assert_eq!(unsafe { *ptr.add(47) }, i as u8);
+                            unsafe { theirs.dealloc(ptr, block) };
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Chunks are handed out until the heap runs out
+        let chunk = Layout::from_size_align(RSB_CHUNK_SIZE, 8).unwrap();
+        let chunks = (0..16)
+            .take_while(|_| !unsafe { heap.alloc(chunk) }.is_null())
+            .count();
+        assert_eq!(chunks, 13);
+        assert!(unsafe { heap.alloc(Layout::new::<[u8; 0x10001]>()) }.is_null());
+
+        let file = unsafe { libc::memfd_create(b"empty\0".as_ptr() as _, 0) };
+        assert_eq!(
+            unsafe { libc::ftruncate(file, 2 * RSB_CHUNK_SIZE as libc::off_t) },
+            0
+        );
+        assert!(unsafe { RSBSharedHeap::from_fd(file) }.is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decay() {
+        use std::time::{Duration, Instant};
+
+        static DECAYING: RSBMalloc = RSBMalloc::new();
+
+        let small = Layout::new::<[u8; 24]>();
+        let blocks: Vec<*mut u8> = (0..3000)
+            .map(|_| unsafe { DECAYING.alloc(small) })
+            .collect();
+        for &block in &blocks {
+            unsafe { DECAYING.dealloc(block, small) };
+        }
+        assert_eq!(DECAYING.mapped_bytes(), 2 * RSB_CHUNK_SIZE);
+
+        assert!(DECAYING.start_decay(Duration::from_millis(10)));
+        assert!(!DECAYING.start_decay(Duration::from_millis(10)));
+        // A pass over every cache takes Miri over a second of its clock
+        let timeout = Duration::from_secs(if cfg!(miri) { 30 } else { 5 });
+        let started = Instant::now();
+        while DECAYING.mapped_bytes() > 0 && started.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(5));
+        }
+        DECAYING.stop_decay();
+        assert_eq!(DECAYING.mapped_bytes(), 0);
+
+        unsafe {
+            let a = DECAYING.alloc(small);
+            assert!(!a.is_null());
+            DECAYING.dealloc(a, small);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn decay_with_cross_cache_frees() {
+        use std::time::Duration;
+
+        static DECAYING: RSBMalloc = RSBMalloc::new();
+
+        assert!(DECAYING.start_decay(Duration::from_millis(1)));
+        // Long enough for a pair's bins to sit idle and have their chunks
+        // released while the other pairs carry on
+        let pause = Duration::from_millis(5);
+        free_across_caches(&DECAYING, pause, || thread::sleep(pause));
+        DECAYING.stop_decay();
+        assert_eq!(DECAYING.stats().classes[3].used, 0);
+    }
+
+    #[test]
+    fn contended_bin() {
+        static BIN: Bin = Bin::new(32, 32);
+        static BUDGET: Budget = Budget::new();
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                thread::spawn(move || {
+                    // Odd threads take and return their slots in batches
+                    let batched = i % 2 == 1;
+                    let mut held = vec![ptr::null_mut(); 16];
+                    for round in 0..ROUNDS {
+                        if batched {
+                            let filled =
+                                unsafe { BIN.alloc_many(&PAGE_ALLOCATOR, &BUDGET, &mut held) };
+                            assert_eq!(filled, held.len());
+                        } else {
+                            for ptr in held.iter_mut() {
+                                *ptr = unsafe { BIN.alloc(&PAGE_ALLOCATOR, &BUDGET) };
+                            }
+                        }
+                        for &ptr in &held {
+                            assert!(!ptr.is_null());
+                            unsafe { fill(ptr, i as u8, 32) };
+                        }
+                        for &ptr in &held {
+                            assert!(unsafe { core::slice::from_raw_parts(ptr, 32) }
+                                .iter()
+                                .all(|&byte| byte == i as u8));
+                        }
+                        if batched {
+                            unsafe { BIN.dealloc_many(&held) };
+                        } else {
+                            for &ptr in &held {
+                                unsafe { BIN.dealloc(ptr) };
+                            }
+                        }
+                        if round % (ROUNDS / 4) == 0 {
+                            BIN.trim(
+                                &mut 0,
+                                &PAGE_ALLOCATOR,
+                                &BUDGET,
+                                TrimMode::Eager,
+                                &|visit| visit(&BIN.free_head),
+                            );
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let usage = BIN.usage();
+        assert_eq!(usage.used(), 0);
+        assert_eq!(usage.free, usage.carved);
+    }
+
+    #[test]
+    fn alloc_batch() {
+        let allocator = RSBMalloc::new();
+        let small = Layout::new::<[u8; 24]>();
+        let mut blocks = vec![ptr::null_mut(); 3000];
+        assert_eq!(allocator.alloc_batch(small, &mut blocks), 3000);
+        let mut sorted = blocks.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 3000);
+        assert!(!sorted[0].is_null());
+        assert_eq!(allocator.stats().classes[3].used, 3000);
+        unsafe { allocator.dealloc_batch(small, &blocks) };
+        assert_eq!(allocator.stats().classes[3].used, 0);
+
+        // Freed blocks are handed out again before anything new is carved
+        assert_eq!(allocator.alloc_batch(small, &mut blocks[..10]), 10);
+        assert_eq!(allocator.mapped_bytes(), 2 * RSB_CHUNK_SIZE);
+        unsafe { allocator.dealloc_batch(small, &blocks[..10]) };
+
+        let large = Layout::from_size_align(RSB_CHUNK_SIZE * 2, 8).unwrap();
+        let mut blocks = [ptr::null_mut(); 3];
+        assert_eq!(allocator.alloc_batch(large, &mut blocks), 3);
+        assert!(blocks.iter().all(|block| !block.is_null()));
+        unsafe { allocator.dealloc_batch(large, &blocks) };
+        assert_eq!(allocator.mapped_bytes(), 2 * RSB_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn pool() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Particle {
+            position: [f32; 3],
+            velocity: [f32; 3],
+        }
+
+        impl Drop for Particle {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let pool = Pool::new();
+        assert_eq!(pool.slot_size(), 24);
+        let particles: Vec<PoolBox<Particle>> = (0..3000)
+            .map(|i| {
+                let particle = Particle {
+                    position: [i as f32; 3],
+                    velocity: [0.0; 3],
+                };
+                pool.alloc(particle).ok().unwrap()
+            })
+            .collect();
+        // 2730 fit in a chunk, with 16 bytes left over
+        assert_eq!(pool.mapped_bytes(), 2 * RSB_CHUNK_SIZE);
+        let mut addresses: Vec<usize> = particles
+            .iter()
+            .map(|particle| &**particle as *const Particle as usize)
+            .collect();
+        addresses.sort_unstable();
+        assert!(addresses.windows(2).all(|pair| pair[1] - pair[0] >= 24));
+        for (i, particle) in particles.iter().enumerate() {
+            assert_eq!(particle.position, [i as f32; 3]);
+            assert_eq!(particle.velocity, [0.0; 3]);
+        }
+        drop(particles);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 3000);
+
+        let particle = pool
+            .alloc(Particle {
+                position: [1.0; 3],
+                velocity: [2.0; 3],
+            })
+            .ok()
+            .unwrap();
+        let particle = PoolBox::into_inner(particle);
+        assert_eq!(particle.velocity, [2.0; 3]);
+        drop(particle);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 3001);
+        assert_eq!(pool.mapped_bytes(), 2 * RSB_CHUNK_SIZE);
+    }
+
+    #[test]
+    // Values are moved into their slots with plain writes, which Miri
+    // reports against a racing pop's read of the slot's link
+    #[cfg_attr(miri, ignore)]
+    fn pool_shared() {
+        static SHARED: Pool<[u64; 5]> = Pool::new();
+        let threads: Vec<_> = (0..8u64)
+            .map(|i| {
+                thread::spawn(move || {
+                    for _ in 0..ROUNDS / 2 {
+                        let values: Vec<_> = (0..16)
+                            .map(|j| SHARED.alloc([i * 16 + j; 5]).unwrap())
+                            .collect();
+                        for (j, value) in values.iter().enumerate() {
+                            assert_eq!(**value, [i * 16 + j as u64; 5]);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn arena() {
+        let mut arena = Arena::new();
+        let word = Layout::new::<u64>();
+        unsafe {
+            let a = arena.alloc(word);
+            let b = arena.alloc(Layout::new::<u8>());
+            let c = arena.alloc(word);
+            assert_eq!(b, a.add(8));
+            assert_eq!(c, a.add(16));
+
+            // Only the latest allocation can be freed or grown in place
+            arena.dealloc(b, Layout::new::<u8>());
+            assert_eq!(arena.alloc(Layout::new::<u8>()), a.add(24));
+            arena.dealloc(c, word);
+            assert_eq!(arena.alloc(word), a.add(32));
+            let grown = arena.realloc(a.add(32), word, 64);
+            assert_eq!(grown, a.add(32));
+            let aligned = arena.alloc(Layout::from_size_align(16, 0x1000).unwrap());
+            assert_eq!(aligned as usize % 0x1000, 0);
+        }
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE);
+
+        let checkpoint = arena.checkpoint();
+        let big = Layout::from_size_align(RSB_CHUNK_SIZE * 3, 8).unwrap();
+        unsafe {
+            let big = arena.alloc(big);
+            big.write_bytes(1, RSB_CHUNK_SIZE * 3);
+            assert!(!arena.alloc(word).is_null());
+        }
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE * 5);
+        unsafe { assert!(arena.rollback(checkpoint)) };
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE);
+        assert_eq!(arena.checkpoint(), checkpoint);
+
+        let sum = arena.scope(|arena| unsafe {
+            let values = arena.alloc(Layout::new::<[u64; 100]>()) as *mut u64;
+            for i in 0..100 {
+                values.add(i).write(i as u64);
+            }
+            (0..100).map(|i| *values.add(i)).sum::<u64>()
+        });
+        assert_eq!(sum, 4950);
+        assert_eq!(arena.checkpoint(), checkpoint);
+
+        for _ in 0..3 {
+            unsafe { arena.alloc(Layout::new::<[u8; 0x8000]>()) };
+        }
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE * 2);
+        let unmapped = arena.checkpoint();
+        arena.reset();
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE);
+        unsafe { assert!(!arena.rollback(unmapped)) };
+
+        // The second chunk is mapped again, likely at the same address, but
+        // that doesn't bring back a checkpoint from before it was unmapped
+        for _ in 0..3 {
+            unsafe { arena.alloc(Layout::new::<[u8; 0x8000]>()) };
+        }
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE * 2);
+        assert_ne!(arena.checkpoint(), unmapped);
+        unsafe { assert!(!arena.rollback(unmapped)) };
+        assert_eq!(arena.mapped_bytes(), RSB_CHUNK_SIZE * 2);
+    }
+
+    #[test]
+    fn arena_over_aligned() {
+        // Chunks aligned past a page must be freed with the same alignment,
+        // which the system-allocator backend under Miri checks
+        let layout = Layout::from_size_align(64, RSB_CHUNK_SIZE * 2).unwrap();
+        let mut arena = Arena::new();
+        unsafe {
+            assert!(!arena.alloc(Layout::new::<u64>()).is_null());
+            let aligned = arena.alloc(layout);
+            assert_eq!(aligned as usize % layout.align(), 0);
+        }
+        arena.reset();
+        unsafe {
+            let aligned = arena.alloc(layout);
+            assert_eq!(aligned as usize % layout.align(), 0);
+        }
+        drop(arena);
+
+        let mut arena = Arena::new();
+        unsafe { assert!(!arena.alloc(layout).is_null()) };
+        arena.reset();
+    }
+
+    #[test]
+    #[cfg(feature = "allocator_api")]
+    fn arena_allocator() {
+        let arena = Arena::new();
+        let mut values = Vec::new_in(&arena);
+        values.extend(0..1000u32);
+        assert_eq!(values.iter().sum::<u32>(), 499500);
+        let boxed = std::boxed::Box::new_in(5u8, &arena);
+        assert_eq!(*boxed, 5);
+    }
+
+    #[test]
+    fn segments() {
+        let allocator = RSBMalloc::new();
+        let small = Layout::new::<[u8; 24]>();
+        let blocks: Vec<*mut u8> = (0..3000)
+            .map(|_| unsafe { allocator.alloc(small) })
+            .collect();
+        let mut chunks: Vec<usize> = blocks
+            .iter()
+            .map(|&block| block as usize & !(RSB_CHUNK_SIZE - 1))
+            .collect();
+        chunks.sort_unstable();
+        chunks.dedup();
+        assert_eq!(chunks.len(), 2);
+        for &block in &blocks {
+            assert_eq!(allocator.usable_size(block), Some(32));
+        }
+
+        let large = Layout::from_size_align(100_000, 8).unwrap();
+        let local = 0u8;
+        unsafe {
+            let big = allocator.alloc(large);
+            assert_eq!(
+                allocator.usable_size(big),
+                Some(limit::mapped_size(100_000))
+            );
+            allocator.dealloc(big, large);
+            assert_eq!(allocator.usable_size(&local), None);
+            for &block in &blocks {
+                allocator.dealloc(block, small);
+            }
+        }
+        allocator.trim(0);
+        assert_eq!(allocator.mapped_bytes(), 0);
+    }
+
+    #[test]
+    fn realloc_in_place() {
+        let allocator = RSBMalloc::new();
+        for class in 0..NUM_CLASSES {
+            let size = 4 << class;
+            unsafe {
+                let layout = Layout::from_size_align(size / 2 + 1, 1).unwrap();
+                let ptr = allocator.alloc(layout);
+                for i in 0..layout.size() {
+                    *ptr.add(i) = i as u8;
+                }
+
+                // Anywhere within the class keeps the block
+                assert_eq!(allocator.realloc(ptr, layout, size), ptr);
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                assert_eq!(allocator.realloc(ptr, layout, size / 2 + 1), ptr);
+
+                // One byte past it moves to the next class, or to a mapping
+                let grown = allocator.realloc(ptr, layout, size + 1);
+                assert_ne!(grown, ptr);
+                assert_eq!(allocator.stats().classes[class].used, 0);
+                let layout = Layout::from_size_align(size + 1, 1).unwrap();
+                *grown.add(size) = 0xaa;
+                for i in 0..size / 2 + 1 {
+                    assert_eq!(*grown.add(i), i as u8);
+                }
+
+                let shrunk = allocator.realloc(grown, layout, size);
+                assert_ne!(shrunk, grown);
+                assert_eq!(allocator.stats().classes[class].used, 1);
+                for i in 0..size / 2 + 1 {
+                    assert_eq!(*shrunk.add(i), i as u8);
+                }
+                allocator.dealloc(shrunk, Layout::from_size_align(size, 1).unwrap());
+            }
+        }
+        let stats = allocator.stats();
+        assert!(stats.classes.iter().all(|class| class.used == 0));
+        assert_eq!(stats.large_mappings, 0);
+    }
+
+    #[test]
+    #[cfg(all(feature = "percpu", target_os = "linux"))]
+    fn per_cpu_caches() {
+        // The thread can migrate between the two reads, so allow a few tries
+        assert!((0..100).any(|_| {
+            let sched = unsafe { libc::sched_getcpu() } as usize;
+            cpu::current_cpu() == sched
+        }));
+
+        let allocator = RSBMalloc::new();
+        let layout = Layout::new::<[u8; 24]>();
+        let (ptr, cache) = unsafe { allocator.alloc_inner(layout) };
+        assert!(cache.unwrap() < cpu::possible_cpus());
+        let mut caches = 0;
+        allocator.stats_by_cache(|_, _| caches += 1);
+        assert_eq!(caches, 1);
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
     fn test_global_allocator() {
-        const THREADS: usize = 32;
-        const ITERATIONS: usize = 1000;
+        const THREADS: usize = if cfg!(miri) { 4 } else { 32 };
+        const ITERATIONS: usize = if cfg!(miri) { 10 } else { 1000 };
 
         let mut map = BTreeMap::new();
 
@@ -489,3 +2011,146 @@ mod test {
         .is_err());
     }
 }
+
+/// Exhaustive checks of the concurrent core, run with
+/// `RUSTFLAGS="--cfg loom" cargo test --release loom_test`. They explore up
+/// to three preemptions unless `LOOM_MAX_PREEMPTIONS` says otherwise.
+#[cfg(all(test, loom))]
+mod loom_test {
+    extern crate std;
+    use core::ptr;
+    use std::vec::Vec;
+
+    use loom::{sync::Arc, thread};
+
+    use crate::{page_allocator::PAGE_ALLOCATOR, thread_cache::bins_layout, trim::TrimMode, *};
+
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound.get_or_insert(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn bin_alloc_dealloc() {
+        model(|| {
+            let bin = Arc::new(Bin::new(32, 32));
+            let budget = Arc::new(Budget::new());
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let (bin, budget) = (bin.clone(), budget.clone());
+                    thread::spawn(move || unsafe {
+                        let a = bin.alloc(&PAGE_ALLOCATOR, &budget);
+                        let b = bin.alloc(&PAGE_ALLOCATOR, &budget);
+                        bin.dealloc(a);
+                        [b, bin.alloc(&PAGE_ALLOCATOR, &budget)]
+                    })
+                })
+                .collect();
+            let mut live: Vec<*mut u8> = threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect();
+            live.sort_unstable();
+            live.dedup();
+            assert_eq!(live.len(), 4, "a slot was handed out twice");
+            assert!(!live.contains(&ptr::null_mut()));
+            for &ptr in &live {
+                unsafe { bin.dealloc(ptr) };
+            }
+            assert_eq!(bin.usage().used(), 0);
+            bin.trim(
+                &mut 0,
+                &PAGE_ALLOCATOR,
+                &budget,
+                TrimMode::Eager,
+                &|visit| visit(&bin.free_head),
+            );
+            assert_eq!(bin.usage().chunks, 0);
+        });
+    }
+
+    /// A slot carved by one cache's bin is freed onto another's, popped off
+    /// it and freed back while the first bin is trimmed, with a second pop
+    /// on the other list that may be reading the slot's link all along
+    #[test]
+    fn trim_with_cross_cache_frees() {
+        model(|| {
+            let bins = Arc::new([Bin::new(32, 32), Bin::new(32, 32)]);
+            let budget = Arc::new(Budget::new());
+            unsafe {
+                let slot = bins[0].alloc(&PAGE_ALLOCATOR, &budget);
+                bins[1].dealloc(slot);
+            }
+            let threads: Vec<_> = (0..3)
+                .map(|i| {
+                    let (bins, budget) = (bins.clone(), budget.clone());
+                    thread::spawn(move || unsafe {
+                        match i {
+                            0 => {
+                                let slot = bins[1].alloc(&PAGE_ALLOCATOR, &budget);
+                                bins[0].dealloc(slot);
+                            }
+                            1 => {
+                                let slot = bins[1].alloc(&PAGE_ALLOCATOR, &budget);
+                                bins[1].dealloc(slot);
+                            }
+                            _ => {
+                                let lists = |visit: &mut dyn FnMut(&FreeList)| {
+                                    visit(&bins[0].free_head);
+                                    visit(&bins[1].free_head);
+                                };
+                                bins[0].trim(
+                                    &mut 0,
+                                    &PAGE_ALLOCATOR,
+                                    &budget,
+                                    TrimMode::Eager,
+                                    &lists,
+                                );
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            for bin in bins.iter() {
+                let lists = |visit: &mut dyn FnMut(&FreeList)| visit(&bin.free_head);
+                bin.trim(&mut 0, &PAGE_ALLOCATOR, &budget, TrimMode::Eager, &lists);
+            }
+        });
+    }
+
+    #[test]
+    fn thread_cache_init() {
+        model(|| {
+            let cache = Arc::new(ThreadCache::new());
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let cache = cache.clone();
+                    thread::spawn(move || unsafe {
+                        let (index, bins) = cache.get_thread_cache();
+                        (index, bins as *const Bins as usize)
+                    })
+                })
+                .collect();
+            let seen: Vec<_> = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect();
+            // Whoever lost the race got the winner's caches
+            let bins_slice = cache.bins.get().unwrap();
+            for (index, bins) in seen {
+                assert!(index < bins_slice.len);
+                assert_eq!(bins, unsafe { bins_slice.ptr.add(index) } as usize);
+            }
+            unsafe {
+                for i in 0..bins_slice.len {
+                    ptr::drop_in_place(bins_slice.ptr.add(i));
+                }
+                PAGE_ALLOCATOR.dealloc(bins_slice.ptr as *mut u8, bins_layout(bins_slice.len));
+            }
+        });
+    }
+}
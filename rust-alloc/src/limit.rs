@@ -0,0 +1,108 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    guard,
+    page_allocator::{PageSource, PAGE_SIZE},
+    RSBMalloc,
+};
+
+/// Called with the size of a request that hit the memory limit or couldn't
+/// be mapped. Return true after freeing memory (or raising the limit) to have
+/// the request retried, false to let it fail with null. A request is retried
+/// at most `MAX_OOM_RETRIES` times before it fails anyway.
+pub type OomHandler = fn(usize) -> bool;
+
+/// How many times one request calls the OOM handler, so a handler that
+/// always returns true can't retry forever
+pub const MAX_OOM_RETRIES: usize = 8;
+
+/// Bytes mapped for bin chunks and large allocations, checked against an
+/// optional cap
+pub(crate) struct Budget {
+    mapped: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl Budget {
+    pub(crate) const fn new() -> Self {
+        Self {
+            mapped: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    /// Accounts for `bytes` about to be mapped. Returns false, leaving the
+    /// count unchanged, if that would exceed the limit.
+    pub(crate) fn try_charge(&self, bytes: usize) -> bool {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.mapped
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mapped| {
+                mapped.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn refund(&self, bytes: usize) {
+        self.mapped.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// What `PAGE_ALLOCATOR` actually maps for `size` bytes
+pub(crate) fn mapped_size(size: usize) -> usize {
+    let page_size = *PAGE_SIZE;
+    (size + page_size - 1) & !(page_size - 1)
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Caps the bytes mapped for bins and large allocations. Once reached,
+    /// requests needing a new mapping call the OOM handler and then return
+    /// null. `None` removes the cap.
+    ///
+    /// Lowering the limit below what's already mapped doesn't release
+    /// anything; it only stops further growth.
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.budget
+            .limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        match self.budget.limit.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Bytes currently mapped for bin chunks and large allocations
+    pub fn mapped_bytes(&self) -> usize {
+        self.budget.mapped.load(Ordering::Relaxed)
+    }
+
+    /// Installs `handler` to run whenever an allocation is about to fail for
+    /// lack of memory. `None` removes it.
+    ///
+    /// The handler may allocate, but a request failing while it runs returns
+    /// null straight away instead of calling it again. With `std` this is
+    /// tracked per thread; without it, other threads' failures skip the
+    /// handler while it's running.
+    pub fn set_oom_handler(&self, handler: Option<OomHandler>) {
+        *self.oom_handler.lock() = handler;
+    }
+
+    /// Gives the OOM handler a chance to make room for `size` bytes, on a
+    /// request's `attempt`th failure counting from 0. True means the request
+    /// should be retried.
+    #[cold]
+    pub(crate) fn handle_oom(&self, size: usize, attempt: usize) -> bool {
+        if attempt >= MAX_OOM_RETRIES {
+            return false;
+        }
+        let handler = *self.oom_handler.lock();
+        match handler {
+            Some(handler) => {
+                guard::enter(&guard::IN_OOM_HANDLER, || handler(size)).unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+}
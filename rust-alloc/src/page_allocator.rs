@@ -1,15 +1,34 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cmp::{max, min},
+    cmp::min,
     ptr,
 };
 use lazy_static::lazy_static;
 
+use crate::{segment, RSB_CHUNK_SIZE};
+
+// The static region replaces the OS outside this crate's own tests, which
+// need pages before any test could hand a region over
+#[cfg(all(miri, any(test, not(feature = "static_region"))))]
+mod heap;
+#[cfg(all(not(miri), any(test, not(feature = "static_region"))))]
+mod os;
+#[cfg(feature = "static_region")]
+mod region;
+
+#[cfg(feature = "static_region")]
+pub use region::StaticRegion;
+
 lazy_static! {
     pub static ref PAGE_SIZE: usize = page_size();
 }
 
-#[cfg(unix)]
+#[cfg(all(feature = "static_region", not(test)))]
+fn page_size() -> usize {
+    0x1000
+}
+
+#[cfg(all(unix, any(test, not(feature = "static_region"))))]
 fn page_size() -> usize {
     #[cfg(target_os = "macos")]
     unsafe {
@@ -21,7 +40,7 @@ fn page_size() -> usize {
     }
 }
 
-#[cfg(windows)]
+#[cfg(all(windows, any(test, not(feature = "static_region"))))]
 fn page_size() -> usize {
     unsafe {
         let mut info = core::mem::zeroed();
@@ -35,117 +54,241 @@ pub struct PageAllocator {}
 
 pub static PAGE_ALLOCATOR: PageAllocator = PageAllocator {};
 
+/// Access allowed to a range of committed pages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+    NoAccess,
+    Read,
+    ReadWrite,
+}
+
+/// Where pages come from: the OS normally, a static region with the
+/// `static_region` feature, or under Miri, which can't map memory, the system
+/// allocator. `PageAllocator` rounds requests to whole
+/// pages and passes them on, so the rules on its methods apply here too.
+pub(crate) trait Backend {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8;
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8;
+    fn reserve(&self, len: usize) -> *mut u8;
+    fn reserve_aligned(&self, len: usize, align: usize) -> *mut u8;
+    unsafe fn commit(&self, ptr: *mut u8, len: usize) -> bool;
+    unsafe fn decommit(&self, ptr: *mut u8, len: usize) -> bool;
+    unsafe fn protect(&self, ptr: *mut u8, len: usize, protection: Protection) -> bool;
+    unsafe fn release(&self, ptr: *mut u8, len: usize) -> bool;
+    unsafe fn purge(&self, ptr: *mut u8, len: usize);
+    unsafe fn purge_lazily(&self, ptr: *mut u8, len: usize);
+}
+
+#[cfg(all(not(miri), any(test, not(feature = "static_region"))))]
+static BACKEND: os::Os = os::Os;
+#[cfg(all(miri, any(test, not(feature = "static_region"))))]
+static BACKEND: heap::Heap = heap::Heap;
+#[cfg(all(feature = "static_region", not(test)))]
+static BACKEND: StaticRegion = StaticRegion::new();
+
+/// Serving pages from a region of memory instead of the OS
+#[cfg(all(feature = "static_region", not(test)))]
+impl PageAllocator {
+    /// Hands over the memory every page comes from, typically a
+    /// `static mut [u8; N]`. Until it's called, every request fails. Returns
+    /// false if a region was already set or `region` is too small to use.
+    pub fn set_region(&self, region: &'static mut [u8]) -> bool {
+        BACKEND.init(region)
+    }
+}
+
+/// Reserving address space without backing it, so a region can grow in place
+/// and its addresses never move. Every range passed in must start and end on
+/// a page boundary.
+impl PageAllocator {
+    /// Reserves `len` bytes of address space, rounded up to whole pages,
+    /// without making any of it accessible. Returns null on failure.
+    pub fn reserve(&self, len: usize) -> *mut u8 {
+        match len.checked_add(*PAGE_SIZE - 1) {
+            Some(len) => BACKEND.reserve(len & !(*PAGE_SIZE - 1)),
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Backs reserved pages with memory and makes them readable and
+    /// writable. Fresh pages read as zero. Returns false on failure.
+    ///
+    /// # Safety
+    /// The range must lie in a reservation from `reserve`.
+    pub unsafe fn commit(&self, ptr: *mut u8, len: usize) -> bool {
+        BACKEND.commit(ptr, len)
+    }
+
+    /// Gives the memory behind committed pages back to the OS, leaving them
+    /// reserved but inaccessible until committed again. Returns false on
+    /// failure.
+    ///
+    /// # Safety
+    /// The range must lie in a reservation from `reserve`, and nothing in it
+    /// may be used until it's committed again.
+    pub unsafe fn decommit(&self, ptr: *mut u8, len: usize) -> bool {
+        BACKEND.decommit(ptr, len)
+    }
+
+    /// Changes the access allowed to committed pages. Returns false on
+    /// failure.
+    ///
+    /// # Safety
+    /// The range must be committed, and nothing may access it in ways
+    /// `protection` no longer allows.
+    pub unsafe fn protect(&self, ptr: *mut u8, len: usize, protection: Protection) -> bool {
+        BACKEND.protect(ptr, len, protection)
+    }
+
+    /// Unmaps reserved pages, committed or not. On Windows, only a whole
+    /// reservation can be released. Returns false on failure.
+    ///
+    /// # Safety
+    /// Nothing in the range may be used again.
+    pub unsafe fn release(&self, ptr: *mut u8, len: usize) -> bool {
+        BACKEND.release(ptr, len)
+    }
+}
+
+impl PageAllocator {
+    /// Reserves `len` bytes at a multiple of `align`, a power of two above
+    /// the page size
+    pub(crate) fn reserve_aligned(&self, len: usize, align: usize) -> *mut u8 {
+        BACKEND.reserve_aligned(len, align)
+    }
+
+    /// Hands the physical pages behind `ptr..ptr + len` back to the OS while
+    /// keeping the range mapped. They read as zero when next touched. Both
+    /// ends must be page aligned.
+    pub(crate) unsafe fn purge(&self, ptr: *mut u8, len: usize) {
+        BACKEND.purge(ptr, len)
+    }
+
+    /// Like `purge`, but lets the OS take the pages only once it's short on
+    /// memory, which is much cheaper if they're reused first. Until then they
+    /// keep their contents. Falls back to `purge` where that isn't supported.
+    pub(crate) unsafe fn purge_lazily(&self, ptr: *mut u8, len: usize) {
+        BACKEND.purge_lazily(ptr, len)
+    }
+}
+
 unsafe impl GlobalAlloc for PageAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let aligned_layout = match layout.align_to(max(layout.align(), *PAGE_SIZE)) {
-            Ok(l) => l.pad_to_align(),
-            Err(_) => return ptr::null_mut(),
-        };
-        #[cfg(windows)]
-        {
-            let addr = libc::VirtualAlloc(
-                ptr::null_mut(),
-                aligned_layout.size(),
-                libc::MEM_COMMIT | libc::MEM_RESERVE,
-                libc::PAGE_READWRITE,
-            );
-            addr as _
-        }
-        #[cfg(unix)]
-        {
-            let addr = libc::mmap(
-                ptr::null_mut(),
-                aligned_layout.size(),
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                -1,
-                0,
-            );
-            addr as _
-        }
+        BACKEND.alloc(layout)
     }
 
     /// Silently fails on errors
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Ok(aligned) = layout.align_to(max(layout.align(), *PAGE_SIZE)) {
-            #[cfg(windows)]
-            libc::VirtualFree(ptr as _, aligned.pad_to_align().size(), libc::MEM_RELEASE);
-            #[cfg(not(windows))]
-            libc::munmap(ptr as _, aligned.pad_to_align().size());
-        }
+        BACKEND.dealloc(ptr, layout)
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let p_size = max(layout.align(), *PAGE_SIZE);
-        let old_aligned_size = match layout.align_to(p_size) {
-            Ok(l) => l.pad_to_align(),
-            Err(_) => return ptr::null_mut(),
-        };
-        let aligned_layout = match Layout::from_size_align(new_size, p_size) {
-            Ok(l) => l.pad_to_align(),
-            Err(_) => return ptr::null_mut(),
-        };
-        let copy_len = min(layout.size(), new_size);
-        #[cfg(windows)]
-        {
-            if new_size <= old_aligned_size.size() {
-                let old_addr_end = ptr.add(old_aligned_size.size());
-                let new_addr_end = ptr.add(aligned_layout.size());
-                if old_addr_end > new_addr_end {
-                    libc::VirtualFree(
-                        new_addr_end as _,
-                        old_aligned_size.size() - aligned_layout.size(),
-                        libc::MEM_DECOMMIT,
-                    );
-                }
-                ptr
-            } else {
-                let new_addr = libc::VirtualAlloc(
-                    ptr::null_mut(),
-                    aligned_layout.size(),
-                    libc::MEM_COMMIT | libc::MEM_RESERVE,
-                    libc::PAGE_READWRITE,
-                ) as _;
-                ptr::copy_nonoverlapping(ptr, new_addr, layout.size());
-                self.dealloc(ptr, layout);
-                new_addr
-            }
+        BACKEND.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Where `RSBMalloc` gets the memory for its bin chunks and large
+/// allocations, so it can run on top of something other than the OS: a
+/// static region, a kernel's frame allocator, shared memory.
+///
+/// Only the memory handed out to callers comes from here. The allocator's
+/// own bookkeeping, such as chunk lists and thread caches, still comes from
+/// `PAGE_ALLOCATOR`.
+///
+/// # Safety
+/// `alloc_pages` must return null or memory fitting `layout` that nothing
+/// else uses until it's passed back to `dealloc_pages`, and likewise for the
+/// other methods. The source is shared between threads.
+pub unsafe trait PageSource: Sync {
+    /// Allocates memory for `layout`, whose size is never zero. Returns null
+    /// on failure.
+    ///
+    /// # Safety
+    /// Same as `GlobalAlloc::alloc`.
+    unsafe fn alloc_pages(&self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    /// Same as `GlobalAlloc::dealloc`.
+    unsafe fn dealloc_pages(&self, ptr: *mut u8, layout: Layout);
+
+    /// Resizes memory from `alloc_pages`, moving it if needed. Returns null,
+    /// leaving the old memory alone, on failure. Defaults to allocating,
+    /// copying and freeing.
+    ///
+    /// # Safety
+    /// Same as `GlobalAlloc::realloc`.
+    unsafe fn realloc_pages(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc_pages(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc_pages(ptr, layout);
         }
-        #[cfg(unix)]
-        {
-            let old_addr_end = ptr.add(old_aligned_size.size());
-            if new_size <= old_aligned_size.size() {
-                let new_addr_end = ptr.add(aligned_layout.size());
-                if old_addr_end > new_addr_end {
-                    libc::munmap(
-                        new_addr_end as _,
-                        old_aligned_size.size() - aligned_layout.size(),
-                    );
-                }
-                ptr
-            } else {
-                let appended_addr = libc::mmap(
-                    old_addr_end as _,
-                    aligned_layout.size() - old_aligned_size.size(),
-                    libc::PROT_READ | libc::PROT_WRITE,
-                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                    -1,
-                    0,
-                ) as *mut u8;
-                if appended_addr == old_addr_end {
-                    ptr
-                } else {
-                    libc::munmap(
-                        appended_addr as _,
-                        aligned_layout.size() - old_aligned_size.size(),
-                    );
-                    let new_addr = self.alloc(aligned_layout);
-
-                    ptr::copy_nonoverlapping(ptr, new_addr, copy_len);
-                    libc::munmap(ptr as _, old_aligned_size.size());
-                    new_addr
-                }
-            }
+        new_ptr
+    }
+
+    /// Allocates a 64 KiB chunk, aligned to its size, for a bin with slots of
+    /// `slot_size`. Defaults to `alloc_pages`.
+    ///
+    /// # Safety
+    /// The chunk must be given back with `dealloc_chunk`.
+    unsafe fn alloc_chunk(&self, slot_size: usize) -> *mut u8 {
+        let _ = slot_size;
+        self.alloc_pages(CHUNK_LAYOUT)
+    }
+
+    /// Gives back a chunk from `alloc_chunk`. Defaults to `dealloc_pages`.
+    ///
+    /// # Safety
+    /// Nothing in the chunk may be used again.
+    unsafe fn dealloc_chunk(&self, chunk: *mut u8) {
+        self.dealloc_pages(chunk, CHUNK_LAYOUT)
+    }
+
+    /// Lets go of the memory behind free pages inside a chunk, keeping them
+    /// usable, when trimming. They may read as anything afterwards. With
+    /// `lazily`, the memory need only be taken once it's needed elsewhere.
+    /// Defaults to doing nothing.
+    ///
+    /// # Safety
+    /// The range is page aligned and lies in a chunk from `alloc_chunk`.
+    unsafe fn purge_pages(&self, ptr: *mut u8, len: usize, lazily: bool) {
+        let _ = (ptr, len, lazily);
+    }
+}
+
+/// Layout of the chunks bins carve slots from
+pub(crate) const CHUNK_LAYOUT: Layout =
+    unsafe { Layout::from_size_align_unchecked(RSB_CHUNK_SIZE, RSB_CHUNK_SIZE) };
+
+/// Chunks come from segments, and free pages are purged
+unsafe impl PageSource for PageAllocator {
+    unsafe fn alloc_pages(&self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    unsafe fn dealloc_pages(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc_pages(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn alloc_chunk(&self, slot_size: usize) -> *mut u8 {
+        segment::alloc_chunk(slot_size)
+    }
+
+    unsafe fn dealloc_chunk(&self, chunk: *mut u8) {
+        segment::dealloc_chunk(chunk)
+    }
+
+    unsafe fn purge_pages(&self, ptr: *mut u8, len: usize, lazily: bool) {
+        if lazily {
+            self.purge_lazily(ptr, len)
+        } else {
+            self.purge(ptr, len)
         }
     }
 }
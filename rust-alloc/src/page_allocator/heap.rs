@@ -0,0 +1,103 @@
+//! Pages from the system allocator, for running under Miri, which can't map
+//! memory. Reservations come back committed and zeroed, and like on Windows
+//! only a whole reservation can be released.
+
+extern crate std;
+
+use core::{alloc::Layout, cmp::max, ptr};
+use std::alloc::{GlobalAlloc, System};
+
+use super::{Backend, Protection, PAGE_SIZE};
+
+pub(super) struct Heap;
+
+impl Heap {
+    /// `layout` grown to whole pages and aligned to at least a page
+    fn pages(layout: Layout) -> Option<Layout> {
+        let layout = layout.align_to(max(layout.align(), *PAGE_SIZE)).ok()?;
+        Some(layout.pad_to_align())
+    }
+
+    /// A reservation is aligned to the largest power of two dividing its
+    /// length, so `release` can tell how it was allocated from the length
+    /// alone
+    fn reservation(len: usize) -> Option<Layout> {
+        Layout::from_size_align(len, len & len.wrapping_neg()).ok()
+    }
+}
+
+impl Backend for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::pages(layout) {
+            Some(pages) => System.alloc_zeroed(pages),
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(pages) = Self::pages(layout) {
+            System.dealloc(ptr, pages);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let (old, new) = match (
+            Self::pages(layout),
+            Layout::from_size_align(new_size, layout.align())
+                .ok()
+                .and_then(Self::pages),
+        ) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return ptr::null_mut(),
+        };
+        let new_ptr = System.realloc(ptr, old, new.size());
+        // Pages past the old end are fresh, so they read as zero
+        if !new_ptr.is_null() && new.size() > old.size() {
+            new_ptr
+                .add(old.size())
+                .write_bytes(0, new.size() - old.size());
+        }
+        new_ptr
+    }
+
+    fn reserve(&self, len: usize) -> *mut u8 {
+        match Self::reservation(len) {
+            Some(layout) => unsafe { System.alloc_zeroed(layout) },
+            None => ptr::null_mut(),
+        }
+    }
+
+    fn reserve_aligned(&self, len: usize, align: usize) -> *mut u8 {
+        debug_assert!(len & len.wrapping_neg() >= align);
+        self.reserve(len)
+    }
+
+    unsafe fn commit(&self, _ptr: *mut u8, _len: usize) -> bool {
+        true
+    }
+
+    unsafe fn decommit(&self, ptr: *mut u8, len: usize) -> bool {
+        self.purge(ptr, len);
+        true
+    }
+
+    unsafe fn protect(&self, _ptr: *mut u8, _len: usize, _protection: Protection) -> bool {
+        true
+    }
+
+    unsafe fn release(&self, ptr: *mut u8, len: usize) -> bool {
+        match Self::reservation(len) {
+            Some(layout) => {
+                System.dealloc(ptr, layout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    unsafe fn purge(&self, ptr: *mut u8, len: usize) {
+        ptr.write_bytes(0, len);
+    }
+
+    unsafe fn purge_lazily(&self, _ptr: *mut u8, _len: usize) {}
+}
@@ -0,0 +1,388 @@
+//! Pages mapped straight from the OS, with `mmap` on Unix and
+//! `VirtualAlloc` on Windows
+
+use core::{
+    alloc::Layout,
+    cmp::{max, min},
+    ptr,
+};
+
+use super::{Backend, Protection, PAGE_SIZE};
+
+pub(super) struct Os;
+
+impl Backend for Os {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let aligned_layout = match layout.align_to(max(layout.align(), *PAGE_SIZE)) {
+            Ok(l) => l.pad_to_align(),
+            Err(_) => return ptr::null_mut(),
+        };
+        if aligned_layout.align() > *PAGE_SIZE {
+            return self.alloc_overaligned(aligned_layout);
+        }
+        #[cfg(windows)]
+        {
+            let addr = libc::VirtualAlloc(
+                ptr::null_mut(),
+                aligned_layout.size(),
+                libc::MEM_COMMIT | libc::MEM_RESERVE,
+                libc::PAGE_READWRITE,
+            );
+            addr as _
+        }
+        #[cfg(unix)]
+        {
+            let addr = libc::mmap(
+                ptr::null_mut(),
+                aligned_layout.size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if addr == libc::MAP_FAILED {
+                ptr::null_mut()
+            } else {
+                addr as _
+            }
+        }
+    }
+
+    /// Silently fails on errors
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Ok(aligned) = layout.align_to(max(layout.align(), *PAGE_SIZE)) {
+            #[cfg(windows)]
+            libc::VirtualFree(ptr as _, aligned.pad_to_align().size(), libc::MEM_RELEASE);
+            #[cfg(not(windows))]
+            libc::munmap(ptr as _, aligned.pad_to_align().size());
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p_size = max(layout.align(), *PAGE_SIZE);
+        let old_aligned_size = match layout.align_to(p_size) {
+            Ok(l) => l.pad_to_align(),
+            Err(_) => return ptr::null_mut(),
+        };
+        let aligned_layout = match Layout::from_size_align(new_size, p_size) {
+            Ok(l) => l.pad_to_align(),
+            Err(_) => return ptr::null_mut(),
+        };
+        let copy_len = min(layout.size(), new_size);
+        #[cfg(windows)]
+        {
+            if new_size <= old_aligned_size.size() {
+                let old_addr_end = ptr.add(old_aligned_size.size());
+                let new_addr_end = ptr.add(aligned_layout.size());
+                if old_addr_end > new_addr_end {
+                    libc::VirtualFree(
+                        new_addr_end as _,
+                        old_aligned_size.size() - aligned_layout.size(),
+                        libc::MEM_DECOMMIT,
+                    );
+                }
+                ptr
+            } else {
+                let new_addr = libc::VirtualAlloc(
+                    ptr::null_mut(),
+                    aligned_layout.size(),
+                    libc::MEM_COMMIT | libc::MEM_RESERVE,
+                    libc::PAGE_READWRITE,
+                ) as *mut u8;
+                if new_addr.is_null() {
+                    return new_addr;
+                }
+                ptr::copy_nonoverlapping(ptr, new_addr, layout.size());
+                self.dealloc(ptr, layout);
+                new_addr
+            }
+        }
+        #[cfg(unix)]
+        {
+            let old_addr_end = ptr.add(old_aligned_size.size());
+            if new_size <= old_aligned_size.size() {
+                let new_addr_end = ptr.add(aligned_layout.size());
+                if old_addr_end > new_addr_end {
+                    libc::munmap(
+                        new_addr_end as _,
+                        old_aligned_size.size() - aligned_layout.size(),
+                    );
+                }
+                ptr
+            } else {
+                let appended_addr = libc::mmap(
+                    old_addr_end as _,
+                    aligned_layout.size() - old_aligned_size.size(),
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                ) as *mut u8;
+                if appended_addr == old_addr_end {
+                    ptr
+                } else {
+                    if appended_addr as *mut libc::c_void != libc::MAP_FAILED {
+                        libc::munmap(
+                            appended_addr as _,
+                            aligned_layout.size() - old_aligned_size.size(),
+                        );
+                    }
+                    let new_addr = self.alloc(aligned_layout);
+                    if new_addr.is_null() {
+                        return new_addr;
+                    }
+
+                    ptr::copy_nonoverlapping(ptr, new_addr, copy_len);
+                    libc::munmap(ptr as _, old_aligned_size.size());
+                    new_addr
+                }
+            }
+        }
+    }
+
+    fn reserve(&self, len: usize) -> *mut u8 {
+        #[cfg(windows)]
+        unsafe {
+            libc::VirtualAlloc(ptr::null_mut(), len, libc::MEM_RESERVE, libc::PAGE_NOACCESS) as _
+        }
+        #[cfg(unix)]
+        unsafe {
+            let addr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | MAP_NORESERVE,
+                -1,
+                0,
+            );
+            if addr == libc::MAP_FAILED {
+                ptr::null_mut()
+            } else {
+                addr as _
+            }
+        }
+    }
+
+    unsafe fn commit(&self, ptr: *mut u8, len: usize) -> bool {
+        #[cfg(windows)]
+        {
+            !libc::VirtualAlloc(ptr as _, len, libc::MEM_COMMIT, libc::PAGE_READWRITE).is_null()
+        }
+        #[cfg(unix)]
+        {
+            libc::mprotect(ptr as _, len, libc::PROT_READ | libc::PROT_WRITE) == 0
+        }
+    }
+
+    unsafe fn decommit(&self, ptr: *mut u8, len: usize) -> bool {
+        #[cfg(windows)]
+        {
+            libc::VirtualFree(ptr as _, len, libc::MEM_DECOMMIT) != 0
+        }
+        // Mapping fresh pages over the range drops both the pages and their
+        // commit charge, which `madvise` alone wouldn't
+        #[cfg(unix)]
+        {
+            libc::mmap(
+                ptr as _,
+                len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | MAP_NORESERVE,
+                -1,
+                0,
+            ) != libc::MAP_FAILED
+        }
+    }
+
+    unsafe fn protect(&self, ptr: *mut u8, len: usize, protection: Protection) -> bool {
+        #[cfg(windows)]
+        {
+            let flags = match protection {
+                Protection::NoAccess => libc::PAGE_NOACCESS,
+                Protection::Read => libc::PAGE_READONLY,
+                Protection::ReadWrite => libc::PAGE_READWRITE,
+            };
+            let mut old = 0;
+            libc::VirtualProtect(ptr as _, len, flags, &mut old) != 0
+        }
+        #[cfg(unix)]
+        {
+            let flags = match protection {
+                Protection::NoAccess => libc::PROT_NONE,
+                Protection::Read => libc::PROT_READ,
+                Protection::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+            };
+            libc::mprotect(ptr as _, len, flags) == 0
+        }
+    }
+
+    unsafe fn release(&self, ptr: *mut u8, len: usize) -> bool {
+        #[cfg(windows)]
+        {
+            let _ = len;
+            libc::VirtualFree(ptr as _, 0, libc::MEM_RELEASE) != 0
+        }
+        #[cfg(unix)]
+        {
+            libc::munmap(ptr as _, len) == 0
+        }
+    }
+
+    /// Reserves `len` bytes at a multiple of `align`, a power of two above
+    /// the page size, by reserving extra and releasing what's outside the
+    /// aligned part
+    #[cfg(unix)]
+    fn reserve_aligned(&self, len: usize, align: usize) -> *mut u8 {
+        let padded = match len.checked_add(align) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let addr = self.reserve(padded);
+        if addr.is_null() {
+            return addr;
+        }
+        let start = addr as usize;
+        let aligned = (start + align - 1) & !(align - 1);
+        unsafe {
+            if aligned > start {
+                self.release(addr, aligned - start);
+            }
+            if start + padded > aligned + len {
+                self.release((aligned + len) as _, start + padded - aligned - len);
+            }
+        }
+        aligned as _
+    }
+
+    /// Windows can't release part of a reservation, so this finds an aligned
+    /// address the way `alloc_overaligned` does
+    #[cfg(windows)]
+    fn reserve_aligned(&self, len: usize, align: usize) -> *mut u8 {
+        let padded = match len.checked_add(align) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        for _ in 0..8 {
+            let addr = self.reserve(padded);
+            if addr.is_null() {
+                return addr;
+            }
+            unsafe { self.release(addr, padded) };
+            let aligned = (addr as usize + align - 1) & !(align - 1);
+            let addr = unsafe {
+                libc::VirtualAlloc(aligned as _, len, libc::MEM_RESERVE, libc::PAGE_NOACCESS)
+            };
+            if !addr.is_null() {
+                return addr as _;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    /// Hands the physical pages behind `ptr..ptr + len` back to the OS while
+    /// keeping the range mapped. They read as zero when next touched. Both
+    /// ends must be page aligned.
+    unsafe fn purge(&self, ptr: *mut u8, len: usize) {
+        #[cfg(windows)]
+        {
+            libc::VirtualFree(ptr as _, len, libc::MEM_DECOMMIT);
+            libc::VirtualAlloc(ptr as _, len, libc::MEM_COMMIT, libc::PAGE_READWRITE);
+        }
+        #[cfg(unix)]
+        libc::madvise(ptr as _, len, libc::MADV_DONTNEED);
+    }
+
+    /// Like `purge`, but lets the OS take the pages only once it's short on
+    /// memory, which is much cheaper if they're reused first. Until then they
+    /// keep their contents. Falls back to `purge` where that isn't supported.
+    unsafe fn purge_lazily(&self, ptr: *mut u8, len: usize) {
+        #[cfg(windows)]
+        libc::VirtualAlloc(ptr as _, len, libc::MEM_RESET, libc::PAGE_READWRITE);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            target_os = "ios"
+        ))]
+        if libc::madvise(ptr as _, len, libc::MADV_FREE) == 0 {
+            return;
+        }
+        #[cfg(unix)]
+        self.purge(ptr, len);
+    }
+}
+
+impl Os {
+    /// Maps `layout` at an alignment above the page size by mapping enough
+    /// extra to find an aligned start, then giving back the excess
+    #[cfg(unix)]
+    unsafe fn alloc_overaligned(&self, layout: Layout) -> *mut u8 {
+        let padded = match layout.size().checked_add(layout.align() - *PAGE_SIZE) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let addr = libc::mmap(
+            ptr::null_mut(),
+            padded,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return ptr::null_mut();
+        }
+        let start = addr as usize;
+        let aligned = (start + layout.align() - 1) & !(layout.align() - 1);
+        if aligned > start {
+            libc::munmap(addr, aligned - start);
+        }
+        let end = aligned + layout.size();
+        if start + padded > end {
+            libc::munmap(end as _, start + padded - end);
+        }
+        aligned as _
+    }
+
+    /// Reserves enough to find an aligned start, then releases it and maps
+    /// just the aligned part. Another thread can take the range in between,
+    /// so this retries a few times.
+    #[cfg(windows)]
+    unsafe fn alloc_overaligned(&self, layout: Layout) -> *mut u8 {
+        let padded = match layout.size().checked_add(layout.align() - *PAGE_SIZE) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        for _ in 0..8 {
+            let addr = libc::VirtualAlloc(
+                ptr::null_mut(),
+                padded,
+                libc::MEM_RESERVE,
+                libc::PAGE_NOACCESS,
+            );
+            if addr.is_null() {
+                return ptr::null_mut();
+            }
+            libc::VirtualFree(addr, 0, libc::MEM_RELEASE);
+            let aligned = (addr as usize + layout.align() - 1) & !(layout.align() - 1);
+            let addr = libc::VirtualAlloc(
+                aligned as _,
+                layout.size(),
+                libc::MEM_COMMIT | libc::MEM_RESERVE,
+                libc::PAGE_READWRITE,
+            );
+            if !addr.is_null() {
+                return addr as _;
+            }
+        }
+        ptr::null_mut()
+    }
+}
+
+/// Reserved ranges shouldn't count against overcommit limits until they're
+/// committed, where the OS supports saying so
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAP_NORESERVE: i32 = libc::MAP_NORESERVE;
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+const MAP_NORESERVE: i32 = 0;
@@ -0,0 +1,229 @@
+//! Pages from a static region, for bare-metal targets with no OS to map
+//! memory from. A bitmap at the start of the region tracks which pages are
+//! taken. Without an MMU nothing can be reserved without being backed, so
+//! reserving takes pages out of the region and committing only zeroes them.
+
+use core::{alloc::Layout, cmp::max, mem, ptr};
+
+use spin::Mutex;
+
+use super::{Backend, PageSource, Protection, PAGE_SIZE};
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+/// Hands out the pages of a region of memory given to it once with `init`,
+/// first fit. `PAGE_ALLOCATOR` uses one with the `static_region` feature,
+/// and it can back an `RSBMalloc` of its own as a `PageSource`.
+pub struct StaticRegion {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// First page of the region
+    base: *mut u8,
+    pages: usize,
+    /// One bit per page, set while it's taken, in the region's first pages
+    map: *mut usize,
+}
+
+// The region is only reached through the lock
+unsafe impl Send for State {}
+
+impl StaticRegion {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                base: ptr::null_mut(),
+                pages: 0,
+                map: ptr::null_mut(),
+            }),
+        }
+    }
+
+    /// Starts handing out the whole pages inside `region`. Returns false if
+    /// it already has a region, or if `region` is too small to hold its own
+    /// free map and a page besides.
+    pub fn init(&self, region: &'static mut [u8]) -> bool {
+        let page_size = *PAGE_SIZE;
+        let addr = region.as_mut_ptr() as usize;
+        let skip = addr.wrapping_neg() & (page_size - 1);
+        let pages = region.len().saturating_sub(skip) / page_size;
+        let words = (pages + WORD_BITS - 1) / WORD_BITS;
+        let map_pages = (words * mem::size_of::<usize>() + page_size - 1) / page_size;
+        let mut state = self.state.lock();
+        if !state.base.is_null() || map_pages >= pages {
+            return false;
+        }
+        let base = unsafe { region.as_mut_ptr().add(skip) };
+        let map = base as *mut usize;
+        unsafe { map.write_bytes(0, words) };
+        *state = State { base, pages, map };
+        state.mark(0, map_pages, true);
+        true
+    }
+}
+
+impl Default for StaticRegion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    fn is_taken(&self, page: usize) -> bool {
+        unsafe { *self.map.add(page / WORD_BITS) & (1 << (page % WORD_BITS)) != 0 }
+    }
+
+    fn mark(&mut self, first: usize, count: usize, taken: bool) {
+        for page in first..first + count {
+            let word = unsafe { &mut *self.map.add(page / WORD_BITS) };
+            if taken {
+                *word |= 1 << (page % WORD_BITS);
+            } else {
+                *word &= !(1 << (page % WORD_BITS));
+            }
+        }
+    }
+
+    /// Index of the page at `ptr`, which must be in the region
+    fn page_of(&self, ptr: *mut u8) -> usize {
+        (ptr as usize - self.base as usize) / *PAGE_SIZE
+    }
+
+    /// Takes the first run of `count` free pages starting at a multiple of
+    /// `align`, or returns null if there isn't one
+    fn take(&mut self, count: usize, align: usize) -> *mut u8 {
+        let page_size = *PAGE_SIZE;
+        let mut first = 0;
+        while count > 0 && first + count <= self.pages {
+            let addr = self.base as usize + first * page_size;
+            if addr % align != 0 {
+                first += (align - addr % align) / page_size;
+                continue;
+            }
+            match (first..first + count)
+                .rev()
+                .find(|&page| self.is_taken(page))
+            {
+                Some(taken) => first = taken + 1,
+                None => {
+                    self.mark(first, count, true);
+                    return unsafe { self.base.add(first * page_size) };
+                }
+            }
+        }
+        ptr::null_mut()
+    }
+
+    /// Pages needed for `len` bytes
+    fn pages_for(len: usize) -> Option<usize> {
+        let page_size = *PAGE_SIZE;
+        Some(len.checked_add(page_size - 1)? / page_size)
+    }
+}
+
+impl Backend for StaticRegion {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let count = match State::pages_for(layout.size()) {
+            Some(count) => count,
+            None => return ptr::null_mut(),
+        };
+        let ptr = self
+            .state
+            .lock()
+            .take(count, max(layout.align(), *PAGE_SIZE));
+        if !ptr.is_null() {
+            ptr.write_bytes(0, count * *PAGE_SIZE);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(count) = State::pages_for(layout.size()) {
+            let mut state = self.state.lock();
+            let first = state.page_of(ptr);
+            state.mark(first, count, false);
+        }
+    }
+
+    /// Grows in place if the pages after the block are free, and shrinks in
+    /// place always
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let page_size = *PAGE_SIZE;
+        let (old, new) = match (State::pages_for(layout.size()), State::pages_for(new_size)) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return ptr::null_mut(),
+        };
+        {
+            let mut state = self.state.lock();
+            let first = state.page_of(ptr);
+            if new <= old {
+                state.mark(first + new, old - new, false);
+                return ptr;
+            }
+            if first + new <= state.pages && (first + old..first + new).all(|p| !state.is_taken(p))
+            {
+                state.mark(first + old, new - old, true);
+                drop(state);
+                ptr.add(old * page_size)
+                    .write_bytes(0, (new - old) * page_size);
+                return ptr;
+            }
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size());
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+
+    fn reserve(&self, len: usize) -> *mut u8 {
+        self.reserve_aligned(len, *PAGE_SIZE)
+    }
+
+    fn reserve_aligned(&self, len: usize, align: usize) -> *mut u8 {
+        self.state.lock().take(len / *PAGE_SIZE, align)
+    }
+
+    unsafe fn commit(&self, ptr: *mut u8, len: usize) -> bool {
+        ptr.write_bytes(0, len);
+        true
+    }
+
+    unsafe fn decommit(&self, _ptr: *mut u8, _len: usize) -> bool {
+        true
+    }
+
+    /// There's no MMU to enforce anything short of full access
+    unsafe fn protect(&self, _ptr: *mut u8, _len: usize, protection: Protection) -> bool {
+        protection == Protection::ReadWrite
+    }
+
+    unsafe fn release(&self, ptr: *mut u8, len: usize) -> bool {
+        let mut state = self.state.lock();
+        let first = state.page_of(ptr);
+        state.mark(first, len / *PAGE_SIZE, false);
+        true
+    }
+
+    unsafe fn purge(&self, ptr: *mut u8, len: usize) {
+        ptr.write_bytes(0, len);
+    }
+
+    unsafe fn purge_lazily(&self, _ptr: *mut u8, _len: usize) {}
+}
+
+unsafe impl PageSource for StaticRegion {
+    unsafe fn alloc_pages(&self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    unsafe fn dealloc_pages(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc_pages(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.realloc(ptr, layout, new_size)
+    }
+}
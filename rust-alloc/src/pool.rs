@@ -0,0 +1,161 @@
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    limit::Budget,
+    page_allocator::{PageSource, PAGE_ALLOCATOR},
+    Bin, RSB_CHUNK_SIZE,
+};
+
+/// Pool of `T`s carved from 64 KiB chunks, like a bin but with slots sized
+/// for `T` exactly instead of rounded up to a power of two. Slots are at
+/// least a word, to hold the free-list link.
+///
+/// Freed slots go on a lock-free list for reuse, so a shared pool can hand
+/// out and take back values from any number of threads. Chunks are only
+/// unmapped, all at once, when the pool is dropped.
+pub struct Pool<T> {
+    bin: Bin,
+    budget: Budget,
+    _values: PhantomData<T>,
+}
+
+// Values are moved in and dropped on whichever thread allocates or drops
+// their box, and a pool never hands out a `&T` of its own
+unsafe impl<T: Send> Send for Pool<T> {}
+unsafe impl<T: Send> Sync for Pool<T> {}
+
+impl<T> Pool<T> {
+    /// Types too big or too aligned for a chunk fail to compile
+    const FITS: () = assert!(
+        mem::size_of::<T>() <= RSB_CHUNK_SIZE && mem::align_of::<T>() <= RSB_CHUNK_SIZE,
+        "Pool values must fit in a 64 KiB chunk"
+    );
+
+    const_fn! {
+        #[allow(clippy::let_unit_value)]
+        pub fn new() -> Self {
+            let () = Self::FITS;
+            Self {
+                bin: Bin::new(mem::size_of::<T>(), mem::align_of::<T>()),
+                budget: Budget::new(),
+                _values: PhantomData,
+            }
+        }
+    }
+
+    /// Moves `value` into the pool, or hands it back if no chunk could be
+    /// mapped
+    pub fn alloc(&self, value: T) -> Result<PoolBox<'_, T>, T> {
+        let ptr = unsafe { self.bin.alloc(&PAGE_ALLOCATOR, &self.budget) } as *mut T;
+        match NonNull::new(ptr) {
+            Some(ptr) => {
+                unsafe { ptr.as_ptr().write(value) };
+                Ok(PoolBox { ptr, pool: self })
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Bytes between consecutive values
+    pub fn slot_size(&self) -> usize {
+        self.bin.slot_size()
+    }
+
+    /// Bytes mapped for the pool's chunks
+    pub fn mapped_bytes(&self) -> usize {
+        self.bin.chunks.lock().len() * RSB_CHUNK_SIZE
+    }
+}
+
+impl<T> Default for Pool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        // Every box borrowed the pool, so they're all gone and the chunks
+        // can go back to the source they came from without walking the free
+        // list
+        for chunk in self.bin.chunks.get_mut().as_slice() {
+            unsafe { PAGE_ALLOCATOR.dealloc_chunk(chunk.ptr) };
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("slot_size", &self.slot_size())
+            .field("mapped_bytes", &self.mapped_bytes())
+            .finish()
+    }
+}
+
+/// Owning pointer to a value in a `Pool`, which goes back to the pool when
+/// dropped
+pub struct PoolBox<'a, T> {
+    ptr: NonNull<T>,
+    pool: &'a Pool<T>,
+}
+
+unsafe impl<T: Send> Send for PoolBox<'_, T> {}
+unsafe impl<T: Sync> Sync for PoolBox<'_, T> {}
+
+impl<T> PoolBox<'_, T> {
+    /// Moves the value out, freeing its slot
+    pub fn into_inner(this: Self) -> T {
+        let this = ManuallyDrop::new(this);
+        unsafe {
+            let value = this.ptr.as_ptr().read();
+            this.pool.bin.dealloc(this.ptr.as_ptr() as *mut u8);
+            value
+        }
+    }
+
+    /// Forgets the box, leaving the value alive until the pool is dropped.
+    /// Its destructor never runs.
+    pub fn leak<'b>(this: Self) -> &'b mut T
+    where
+        Self: 'b,
+    {
+        let this = ManuallyDrop::new(this);
+        unsafe { &mut *this.ptr.as_ptr() }
+    }
+}
+
+impl<T> Deref for PoolBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PoolBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for PoolBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.pool.bin.dealloc(self.ptr.as_ptr() as *mut u8);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PoolBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,209 @@
+use core::{
+    alloc::GlobalAlloc,
+    mem,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    limit,
+    page_allocator::{PageSource, CHUNK_LAYOUT, PAGE_ALLOCATOR},
+    RSBMalloc, RSB_CHUNK_SIZE,
+};
+
+#[cfg(all(target_pointer_width = "64", not(miri)))]
+const SEGMENT_SHIFT: u32 = 30;
+/// Miri backs every reservation with real memory, so segments stay small
+#[cfg(any(not(target_pointer_width = "64"), miri))]
+const SEGMENT_SHIFT: u32 = 24;
+/// Bin chunks are carved from reservations this big, aligned to their size
+const SEGMENT_SIZE: usize = 1 << SEGMENT_SHIFT;
+const CHUNKS_PER_SEGMENT: usize = SEGMENT_SIZE / RSB_CHUNK_SIZE;
+/// Past this many segments, chunks are mapped one at a time
+const MAX_SEGMENTS: usize = 256;
+
+/// User space addresses fit in 48 bits on every 64-bit target we run on
+#[cfg(target_pointer_width = "64")]
+const ADDRESS_BITS: u32 = 48;
+#[cfg(not(target_pointer_width = "64"))]
+const ADDRESS_BITS: u32 = usize::BITS;
+const WORD_BITS: usize = usize::BITS as usize;
+const MAP_WORDS: usize = (1 << (ADDRESS_BITS - SEGMENT_SHIFT)) / WORD_BITS;
+
+/// One bit per segment-aligned address, set once a segment is reserved
+/// there. Segments are never released, so a set bit stays valid.
+#[allow(clippy::declare_interior_mutable_const)]
+const UNMAPPED: AtomicUsize = AtomicUsize::new(0);
+static SEGMENT_MAP: [AtomicUsize; MAP_WORDS] = [UNMAPPED; MAP_WORDS];
+
+static SEGMENTS: Mutex<Segments> = Mutex::new(Segments {
+    bases: [0; MAX_SEGMENTS],
+    len: 0,
+});
+
+struct Segments {
+    bases: [usize; MAX_SEGMENTS],
+    len: usize,
+}
+
+/// Bookkeeping at the start of every segment, in its first chunks
+#[repr(C)]
+struct Header {
+    /// Slot size of the bin each chunk belongs to, or 0 if it isn't in use.
+    /// Read without the lock.
+    slot_sizes: [AtomicU32; CHUNKS_PER_SEGMENT],
+    /// Chunks given back and decommitted, ready for reuse
+    free: [u16; CHUNKS_PER_SEGMENT],
+    free_len: usize,
+    /// Chunks from here on have never been handed out
+    fresh: usize,
+}
+
+const HEADER_CHUNKS: usize = (mem::size_of::<Header>() + RSB_CHUNK_SIZE - 1) / RSB_CHUNK_SIZE;
+
+impl Segments {
+    /// Finds a chunk in the segments, reserving a new one if they're full.
+    /// Returns null if that fails too.
+    unsafe fn take_chunk(&mut self) -> *mut u8 {
+        for &base in self.bases[..self.len].iter().rev() {
+            if let Some(index) = take(base as *mut Header) {
+                return (base + index * RSB_CHUNK_SIZE) as *mut u8;
+            }
+        }
+        if self.len == MAX_SEGMENTS {
+            return core::ptr::null_mut();
+        }
+        let header = new_segment();
+        if header.is_null() {
+            return core::ptr::null_mut();
+        }
+        self.bases[self.len] = header as usize;
+        self.len += 1;
+        match take(header) {
+            Some(index) => (header as usize + index * RSB_CHUNK_SIZE) as *mut u8,
+            None => core::ptr::null_mut(),
+        }
+    }
+}
+
+/// Takes a free or fresh chunk's index. Goes through the raw pointer, since
+/// `slot_sizes` may be read at the same time.
+unsafe fn take(header: *mut Header) -> Option<usize> {
+    if (*header).free_len > 0 {
+        (*header).free_len -= 1;
+        Some((*header).free[(*header).free_len] as usize)
+    } else if (*header).fresh < CHUNKS_PER_SEGMENT {
+        (*header).fresh += 1;
+        Some((*header).fresh - 1)
+    } else {
+        None
+    }
+}
+
+/// Reserves a segment and commits its header, which starts out zeroed
+unsafe fn new_segment() -> *mut Header {
+    let base = PAGE_ALLOCATOR.reserve_aligned(SEGMENT_SIZE, SEGMENT_SIZE);
+    if base.is_null() {
+        return core::ptr::null_mut();
+    }
+    let index = base as usize >> SEGMENT_SHIFT;
+    if index >= MAP_WORDS * WORD_BITS
+        || !PAGE_ALLOCATOR.commit(base, HEADER_CHUNKS * RSB_CHUNK_SIZE)
+    {
+        PAGE_ALLOCATOR.release(base, SEGMENT_SIZE);
+        return core::ptr::null_mut();
+    }
+    let header = base as *mut Header;
+    (*header).fresh = HEADER_CHUNKS;
+    SEGMENT_MAP[index / WORD_BITS].fetch_or(1 << (index % WORD_BITS), Ordering::Release);
+    header
+}
+
+/// The header of the segment holding `ptr`, if it's in one
+fn segment_of(ptr: *const u8) -> Option<*mut Header> {
+    let index = ptr as usize >> SEGMENT_SHIFT;
+    let (word, bit) = (index / WORD_BITS, index % WORD_BITS);
+    // Indexed rather than through `get`, which would borrow the whole map
+    // as a slice, a slow retag under Miri on every lookup
+    if word >= MAP_WORDS || SEGMENT_MAP[word].load(Ordering::Acquire) & (1 << bit) == 0 {
+        return None;
+    }
+    Some((ptr as usize & !(SEGMENT_SIZE - 1)) as *mut Header)
+}
+
+fn chunk_index(ptr: *const u8) -> usize {
+    (ptr as usize & (SEGMENT_SIZE - 1)) / RSB_CHUNK_SIZE
+}
+
+/// Maps a chunk for a bin with slots of `slot_size`, carved from a segment
+/// unless they've run out. Returns null on failure.
+pub(crate) unsafe fn alloc_chunk(slot_size: usize) -> *mut u8 {
+    // A static region is much smaller than a segment, so don't look for one
+    if cfg!(all(feature = "static_region", not(test))) {
+        return PAGE_ALLOCATOR.alloc(CHUNK_LAYOUT);
+    }
+    let chunk = SEGMENTS.lock().take_chunk();
+    if chunk.is_null() {
+        return PAGE_ALLOCATOR.alloc(CHUNK_LAYOUT);
+    }
+    if !PAGE_ALLOCATOR.commit(chunk, RSB_CHUNK_SIZE) {
+        dealloc_chunk(chunk);
+        return core::ptr::null_mut();
+    }
+    let header = segment_of(chunk).unwrap();
+    (*header).slot_sizes[chunk_index(chunk)].store(slot_size as u32, Ordering::Release);
+    chunk
+}
+
+/// Gives back a chunk from `alloc_chunk`. A chunk from a segment is
+/// decommitted and kept for reuse, since its segment stays reserved.
+pub(crate) unsafe fn dealloc_chunk(chunk: *mut u8) {
+    let header = match segment_of(chunk) {
+        Some(header) => header,
+        None => return PAGE_ALLOCATOR.dealloc(chunk, CHUNK_LAYOUT),
+    };
+    let index = chunk_index(chunk);
+    (*header).slot_sizes[index].store(0, Ordering::Release);
+    PAGE_ALLOCATOR.decommit(chunk, RSB_CHUNK_SIZE);
+    let _segments = SEGMENTS.lock();
+    (*header).free[(*header).free_len] = index as u16;
+    (*header).free_len += 1;
+}
+
+/// Slot size of the bin chunk holding `ptr`, if it's in a segment
+pub(crate) fn slot_size_of(ptr: *const u8) -> Option<usize> {
+    let header = segment_of(ptr)?;
+    match unsafe { (*header).slot_sizes[chunk_index(ptr)].load(Ordering::Acquire) } {
+        0 => None,
+        slot_size => Some(slot_size as usize),
+    }
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// How many bytes can be used at `ptr`, which must point to the start of
+    /// a block from this allocator, or `None` if it isn't one.
+    ///
+    /// Blocks in bins are found in constant time through the segment they
+    /// were carved from, and large allocations through a hash table. Blocks
+    /// in chunks from outside a segment, such as those from a custom page
+    /// source, are looked up in lists.
+    pub fn usable_size(&self, ptr: *const u8) -> Option<usize> {
+        if let Some(slot_size) = slot_size_of(ptr) {
+            return Some(slot_size);
+        }
+        let chunk = (ptr as usize & !(RSB_CHUNK_SIZE - 1)) as *mut u8;
+        let mut found = None;
+        self.for_each_bins(|_, bins| {
+            for bin in bins.as_array() {
+                if bin.chunks.lock().as_slice().iter().any(|c| c.ptr == chunk) {
+                    found = Some(bin.slot_size());
+                }
+            }
+        });
+        if found.is_some() {
+            return found;
+        }
+        self.large.get(ptr).map(limit::mapped_size)
+    }
+}
@@ -0,0 +1,281 @@
+//! A heap in memory shared between processes, which may each map it at a
+//! different address
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use std::{ffi::CStr, os::unix::io::RawFd};
+
+use spin::Mutex;
+
+use crate::{
+    class_size,
+    free_list::{FreeList, Offsets},
+    size_class, Slice, MAX_ALIGN, NUM_CLASSES, RSB_CHUNK_SIZE,
+};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"rsbshare");
+
+/// Kept in the mapping's first chunk. Everything in it, and every link
+/// between free slots, is an offset from the start of the mapping.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    len: usize,
+    /// Offset of the first chunk no bin has taken yet
+    next_chunk: AtomicUsize,
+    root: AtomicUsize,
+    bins: [SharedBin; NUM_CLASSES],
+}
+
+/// A bin whose chunks are never given back, like a pool's
+struct SharedBin {
+    free_head: FreeList,
+    /// Carves slots by offset rather than address. Chunks start at multiples
+    /// of the chunk size from the start of the mapping, which is all the
+    /// cursor needs.
+    page: Slice,
+    /// Serializes starting new chunks
+    growing: Mutex<()>,
+    slot_size: usize,
+}
+
+/// Heap of blocks up to 64 KiB in a `memfd` or POSIX shared memory object,
+/// which several processes can map and allocate from at once. Blocks can be
+/// freed by any process, not only the one that allocated them.
+///
+/// Each process may see the heap at a different address, so pointers into it
+/// only make sense to the process that made them. Pass `offset_of` a block
+/// instead and turn it back with `at_offset`; `root` holds one such offset
+/// where every process can find it.
+///
+/// The bins use spinlocks inside the mapping, so a process that dies while
+/// allocating can leave the others stuck. Every process must use the same
+/// build of rsbmalloc.
+pub struct RSBSharedHeap {
+    base: *mut u8,
+    len: usize,
+    fd: RawFd,
+}
+
+// Everything shared lives in the mapping, behind atomics and locks
+unsafe impl Send for RSBSharedHeap {}
+unsafe impl Sync for RSBSharedHeap {}
+
+impl RSBSharedHeap {
+    /// Creates a heap of `len` bytes, rounded down to whole 64 KiB chunks, in
+    /// an anonymous `memfd`. Other processes map it with `from_fd`, after
+    /// inheriting the descriptor or receiving it over a Unix socket. Returns
+    /// `None` on failure or if `len` leaves no room past the first chunk,
+    /// which holds the bins.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn create(len: usize) -> Option<Self> {
+        let fd = unsafe { libc::memfd_create(b"rsbmalloc\0".as_ptr() as _, libc::MFD_CLOEXEC) };
+        unsafe { Self::init(fd, len) }
+    }
+
+    /// Like `create`, but in a new POSIX shared memory object called `name`,
+    /// which other processes open with `open_named`. It stays around until
+    /// it's removed with `shm_unlink`.
+    pub fn create_named(name: &CStr, len: usize) -> Option<Self> {
+        let fd = unsafe {
+            libc::shm_open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                0o600,
+            )
+        };
+        let heap = unsafe { Self::init(fd, len) };
+        if heap.is_none() && fd >= 0 {
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+        }
+        heap
+    }
+
+    /// Maps the heap in the shared memory object called `name`
+    pub fn open_named(name: &CStr) -> Option<Self> {
+        unsafe { Self::from_fd(libc::shm_open(name.as_ptr(), libc::O_RDWR, 0)) }
+    }
+
+    /// Maps the heap behind `fd`, which it takes ownership of. Returns `None`
+    /// if that fails or `fd` doesn't hold a heap.
+    ///
+    /// # Safety
+    /// Nothing but rsbmalloc may write to the file behind `fd`.
+    pub unsafe fn from_fd(fd: RawFd) -> Option<Self> {
+        if fd < 0 {
+            return None;
+        }
+        let mut stat = mem::zeroed::<libc::stat>();
+        let len = if libc::fstat(fd, &mut stat) == 0 {
+            stat.st_size as usize
+        } else {
+            0
+        };
+        let heap = Self::map(fd, len)?;
+        let header = heap.header();
+        if header.magic != MAGIC || header.len != len {
+            return None;
+        }
+        Some(heap)
+    }
+
+    /// Sizes the file behind `fd` and sets up its bins
+    unsafe fn init(fd: RawFd, len: usize) -> Option<Self> {
+        if fd < 0 {
+            return None;
+        }
+        let len = len & !(RSB_CHUNK_SIZE - 1);
+        if len < 2 * RSB_CHUNK_SIZE || libc::ftruncate(fd, len as libc::off_t) != 0 {
+            libc::close(fd);
+            return None;
+        }
+        let heap = Self::map(fd, len)?;
+        let header = heap.base as *mut Header;
+        for class in 0..NUM_CLASSES {
+            let slot_size = class_size(class).max(mem::size_of::<usize>());
+            ptr::addr_of_mut!((*header).bins[class]).write(SharedBin::new(slot_size));
+        }
+        ptr::addr_of_mut!((*header).len).write(len);
+        ptr::addr_of_mut!((*header).next_chunk).write(AtomicUsize::new(RSB_CHUNK_SIZE));
+        ptr::addr_of_mut!((*header).root).write(AtomicUsize::new(0));
+        ptr::addr_of_mut!((*header).magic).write(MAGIC);
+        Some(heap)
+    }
+
+    /// Maps `len` bytes of `fd`, closing it on failure
+    unsafe fn map(fd: RawFd, len: usize) -> Option<Self> {
+        let base = if len < mem::size_of::<Header>() {
+            libc::MAP_FAILED
+        } else {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            libc::close(fd);
+            return None;
+        }
+        Some(Self {
+            base: base as *mut u8,
+            len,
+            fd,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.base as *const Header) }
+    }
+
+    fn links(&self) -> Offsets {
+        Offsets(self.base)
+    }
+
+    /// The descriptor of the shared file, to hand to other processes
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Bytes mapped, including the chunk holding the bins
+    pub fn mapped_bytes(&self) -> usize {
+        self.len
+    }
+
+    /// Where `ptr`, which must point into the heap, sits in it, the same in
+    /// every process
+    pub fn offset_of(&self, ptr: *const u8) -> usize {
+        ptr as usize - self.base as usize
+    }
+
+    /// This process's pointer to `offset` in the heap
+    pub fn at_offset(&self, offset: usize) -> *mut u8 {
+        self.base.wrapping_add(offset)
+    }
+
+    /// An offset every process can find, for whatever they agree to look up
+    /// first. Starts out as 0.
+    pub fn root(&self) -> &AtomicUsize {
+        &self.header().root
+    }
+
+    /// Carves a slot for `bin`, taking it a new chunk from the unused part
+    /// of the heap if its current one is used up
+    fn add_one(&self, bin: &SharedBin) -> *mut u8 {
+        let offset = bin.page.carve(bin.slot_size) as usize;
+        if offset != 0 {
+            return self.at_offset(offset);
+        }
+        let _growing = bin.growing.lock();
+        // Another process may have started a chunk while we waited
+        let offset = bin.page.carve(bin.slot_size) as usize;
+        if offset != 0 {
+            return self.at_offset(offset);
+        }
+        let header = self.header();
+        let chunk = header
+            .next_chunk
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |next| {
+                (next + RSB_CHUNK_SIZE <= header.len).then(|| next + RSB_CHUNK_SIZE)
+            });
+        match chunk {
+            Ok(chunk) => {
+                bin.page.start(chunk as *mut u8, bin.slot_size);
+                self.at_offset(chunk)
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+impl SharedBin {
+    fn new(slot_size: usize) -> Self {
+        Self {
+            free_head: FreeList::new(),
+            page: Slice::new(),
+            growing: Mutex::new(()),
+            slot_size,
+        }
+    }
+}
+
+/// Blocks too big for a bin, or aligned to more than a page, aren't served
+unsafe impl GlobalAlloc for RSBSharedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() > MAX_ALIGN {
+            return ptr::null_mut();
+        }
+        let bin = match size_class(layout.pad_to_align().size()) {
+            Some(class) => &self.header().bins[class],
+            None => return ptr::null_mut(),
+        };
+        let slot = bin.free_head.pop_in(self.links());
+        if slot.is_null() {
+            self.add_one(bin)
+        } else {
+            slot
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = size_class(layout.pad_to_align().size()) {
+            let bin = &self.header().bins[class];
+            bin.free_head.push_chain_in(self.links(), ptr, ptr);
+        }
+    }
+}
+
+impl Drop for RSBSharedHeap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as _, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
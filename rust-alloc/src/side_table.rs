@@ -0,0 +1,241 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::{self, MaybeUninit},
+    ptr,
+};
+
+use spin::Mutex;
+
+use crate::page_allocator::{PAGE_ALLOCATOR, PAGE_SIZE};
+
+const SHARDS: usize = 64;
+const EMPTY: usize = 0;
+const TOMBSTONE: usize = 1;
+
+pub(crate) fn hash_usize(input: usize) -> usize {
+    let mut output = input as u64;
+    output ^= output >> 33;
+    output = output.wrapping_mul(0xff51afd7ed558ccd);
+    output ^= output >> 33;
+    output = output.wrapping_mul(0xc4ceb9fe1a85ec53);
+    output ^= output >> 33;
+    output as usize
+}
+
+/// Map from allocation address to some per-allocation data. Lives entirely in
+/// pages from `PAGE_ALLOCATOR` and is sharded by address to limit contention.
+pub(crate) struct SideTable<V: Copy> {
+    shards: [Mutex<Shard<V>>; SHARDS],
+}
+
+struct Entry<V> {
+    key: usize,
+    value: MaybeUninit<V>,
+}
+
+/// Open-addressed with linear probing. Fresh pages are zeroed, so a new
+/// buffer is already all `EMPTY`.
+struct Shard<V> {
+    entries: *mut Entry<V>,
+    cap: usize,
+    len: usize,
+    /// Live entries plus tombstones
+    occupied: usize,
+}
+
+unsafe impl<V: Send> Send for Shard<V> {}
+
+impl<V: Copy> SideTable<V> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_SHARD: Mutex<Shard<V>> = Mutex::new(Shard {
+        entries: ptr::null_mut(),
+        cap: 0,
+        len: 0,
+        occupied: 0,
+    });
+
+    pub(crate) const fn new() -> Self {
+        Self {
+            shards: [Self::EMPTY_SHARD; SHARDS],
+        }
+    }
+
+    fn shard(&self, key: usize) -> (&Mutex<Shard<V>>, usize) {
+        let hash = hash_usize(key);
+        (&self.shards[hash % SHARDS], hash / SHARDS)
+    }
+
+    /// Returns false if the table couldn't grow
+    pub(crate) fn insert(&self, key: usize, value: V) -> bool {
+        let (shard, hash) = self.shard(key);
+        shard.lock().insert(key, hash, value)
+    }
+
+    pub(crate) fn remove(&self, key: usize) -> Option<V> {
+        let (shard, hash) = self.shard(key);
+        shard.lock().remove(key, hash)
+    }
+
+    pub(crate) fn get(&self, key: usize) -> Option<V> {
+        let (shard, hash) = self.shard(key);
+        shard.lock().get(key, hash)
+    }
+
+    #[cfg(feature = "backtrace")]
+    pub(crate) fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len).sum()
+    }
+
+    /// Visits entries one shard at a time. `f` runs with the shard locked, so
+    /// it must not allocate through the allocator that owns this table.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(usize, &V)) {
+        for shard in &self.shards {
+            let shard = shard.lock();
+            for entry in shard.entries() {
+                if entry.key > TOMBSTONE {
+                    f(entry.key, unsafe { entry.value.assume_init_ref() });
+                }
+            }
+        }
+    }
+}
+
+impl<V: Copy> Shard<V> {
+    fn layout(cap: usize) -> Layout {
+        unsafe { Layout::from_size_align_unchecked(cap * mem::size_of::<Entry<V>>(), *PAGE_SIZE) }
+    }
+
+    fn entries(&self) -> &[Entry<V>] {
+        if self.entries.is_null() {
+            &[]
+        } else {
+            unsafe { core::slice::from_raw_parts(self.entries, self.cap) }
+        }
+    }
+
+    fn insert(&mut self, key: usize, hash: usize, value: V) -> bool {
+        if (self.occupied + 1) * 4 > self.cap * 3 && !self.rehash() {
+            return false;
+        }
+        let mask = self.cap - 1;
+        let mut index = hash & mask;
+        let mut slot = None;
+        loop {
+            let entry = unsafe { &mut *self.entries.add(index) };
+            if entry.key == key {
+                entry.value = MaybeUninit::new(value);
+                return true;
+            }
+            if entry.key == TOMBSTONE && slot.is_none() {
+                slot = Some(index);
+            }
+            if entry.key == EMPTY {
+                break;
+            }
+            index = (index + 1) & mask;
+        }
+        let index = match slot {
+            Some(tombstone) => tombstone,
+            None => {
+                self.occupied += 1;
+                index
+            }
+        };
+        unsafe {
+            self.entries.add(index).write(Entry {
+                key,
+                value: MaybeUninit::new(value),
+            })
+        };
+        self.len += 1;
+        true
+    }
+
+    fn get(&self, key: usize, hash: usize) -> Option<V> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.cap - 1;
+        let mut index = hash & mask;
+        loop {
+            let entry = unsafe { &*self.entries.add(index) };
+            if entry.key == key {
+                return Some(unsafe { entry.value.assume_init() });
+            }
+            if entry.key == EMPTY {
+                return None;
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    fn remove(&mut self, key: usize, hash: usize) -> Option<V> {
+        if self.len == 0 {
+            return None;
+        }
+        let mask = self.cap - 1;
+        let mut index = hash & mask;
+        loop {
+            let entry = unsafe { &mut *self.entries.add(index) };
+            if entry.key == key {
+                entry.key = TOMBSTONE;
+                self.len -= 1;
+                return Some(unsafe { entry.value.assume_init() });
+            }
+            if entry.key == EMPTY {
+                return None;
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Moves everything into a fresh buffer, doubling it unless most of the
+    /// occupied slots were tombstones
+    fn rehash(&mut self) -> bool {
+        let new_cap = if self.cap == 0 {
+            *PAGE_SIZE / mem::size_of::<Entry<V>>()
+        } else if self.len * 2 < self.cap / 2 {
+            self.cap
+        } else {
+            self.cap * 2
+        }
+        .next_power_of_two();
+        let new_entries = unsafe { PAGE_ALLOCATOR.alloc(Self::layout(new_cap)) } as *mut Entry<V>;
+        if new_entries.is_null() {
+            return false;
+        }
+        let old_entries = self.entries;
+        let old_cap = self.cap;
+        self.entries = new_entries;
+        self.cap = new_cap;
+        self.len = 0;
+        self.occupied = 0;
+        for i in 0..old_cap {
+            let entry = unsafe { &*old_entries.add(i) };
+            if entry.key > TOMBSTONE {
+                let hash = hash_usize(entry.key) / SHARDS;
+                self.insert(entry.key, hash, unsafe { entry.value.assume_init() });
+            }
+        }
+        if !old_entries.is_null() {
+            unsafe { PAGE_ALLOCATOR.dealloc(old_entries as *mut u8, Self::layout(old_cap)) };
+        }
+        true
+    }
+}
+
+impl<V> Drop for Shard<V> {
+    fn drop(&mut self) {
+        if !self.entries.is_null() {
+            unsafe {
+                PAGE_ALLOCATOR.dealloc(
+                    self.entries as *mut u8,
+                    Layout::from_size_align_unchecked(
+                        self.cap * mem::size_of::<Entry<V>>(),
+                        *PAGE_SIZE,
+                    ),
+                )
+            };
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use core::fmt;
+
+use crate::{page_allocator::PageSource, Bins, RSBMalloc, NUM_CLASSES, RSB_CHUNK_SIZE};
+
+/// Usage of one size class, summed over the caches it was collected from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// Largest allocation the class serves
+    pub size: usize,
+    /// Bytes between consecutive slots
+    pub slot_size: usize,
+    pub chunks: usize,
+    pub used: usize,
+    /// Slots on the free list, ready for reuse
+    pub free: usize,
+}
+
+impl ClassStats {
+    pub fn mapped_bytes(&self) -> usize {
+        self.chunks * RSB_CHUNK_SIZE
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used * self.slot_size
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub classes: [ClassStats; NUM_CLASSES],
+    pub large_mappings: usize,
+    pub large_bytes: usize,
+}
+
+impl Stats {
+    fn add_bins(&mut self, bins: &Bins) {
+        for (class, bin) in self.classes.iter_mut().zip(bins.as_array()) {
+            let usage = bin.usage();
+            class.size = bin.class_size();
+            class.slot_size = bin.slot_size();
+            class.chunks += usage.chunks;
+            class.used += usage.used();
+            class.free += usage.free;
+        }
+    }
+
+    /// Bytes mapped from the OS, bins and large allocations together
+    pub fn mapped_bytes(&self) -> usize {
+        self.classes
+            .iter()
+            .map(ClassStats::mapped_bytes)
+            .sum::<usize>()
+            + self.large_bytes
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.classes
+            .iter()
+            .map(ClassStats::used_bytes)
+            .sum::<usize>()
+            + self.large_bytes
+    }
+
+    /// Share of the bin chunks not holding a live block, whether on a free
+    /// list or not carved yet
+    pub fn fragmentation(&self) -> f64 {
+        let mapped = self.mapped_bytes() - self.large_bytes;
+        if mapped == 0 {
+            0.0
+        } else {
+            1.0 - (self.used_bytes() - self.large_bytes) as f64 / mapped as f64
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.large_mappings == 0 && self.classes.iter().all(|class| class.chunks == 0)
+    }
+
+    fn write_table<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        writeln!(
+            out,
+            "{:>10} {:>8} {:>10} {:>10} {:>14} {:>14}",
+            "size", "chunks", "used", "free", "used bytes", "mapped bytes"
+        )?;
+        for class in self.classes.iter().filter(|class| class.chunks > 0) {
+            writeln!(
+                out,
+                "{:>10} {:>8} {:>10} {:>10} {:>14} {:>14}",
+                class.size,
+                class.chunks,
+                class.used,
+                class.free,
+                class.used_bytes(),
+                class.mapped_bytes()
+            )?;
+        }
+        if self.large_mappings > 0 {
+            writeln!(
+                out,
+                "{:>10} {:>8} {:>10} {:>10} {:>14} {:>14}",
+                "large",
+                self.large_mappings,
+                self.large_mappings,
+                0,
+                self.large_bytes,
+                self.large_bytes
+            )?;
+        }
+        writeln!(
+            out,
+            "{:>10} {:>8} {:>10} {:>10} {:>14} {:>14}",
+            "total",
+            "",
+            "",
+            "",
+            self.used_bytes(),
+            self.mapped_bytes()
+        )?;
+        writeln!(out, "fragmentation: {:.1}%", self.fragmentation() * 100.0)
+    }
+
+    fn write_json<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        write!(out, "{{\"classes\":[")?;
+        for (i, class) in self.classes.iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(
+                out,
+                "{{\"size\":{},\"slot_size\":{},\"chunks\":{},\"used\":{},\"free\":{},\"used_bytes\":{},\"mapped_bytes\":{}}}",
+                class.size,
+                class.slot_size,
+                class.chunks,
+                class.used,
+                class.free,
+                class.used_bytes(),
+                class.mapped_bytes()
+            )?;
+        }
+        write!(
+            out,
+            "],\"large\":{{\"mappings\":{},\"bytes\":{}}},\"used_bytes\":{},\"mapped_bytes\":{},\"fragmentation\":{:.4}}}",
+            self.large_mappings,
+            self.large_bytes,
+            self.used_bytes(),
+            self.mapped_bytes(),
+            self.fragmentation()
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// Aligned columns for people
+    Table,
+    /// A single JSON object
+    Json,
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Usage of every size class across all thread caches, plus large
+    /// mappings
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        self.for_each_bins(|_, bins| stats.add_bins(bins));
+        let (mappings, bytes) = self.large.totals();
+        stats.large_mappings = mappings;
+        stats.large_bytes = bytes;
+        stats
+    }
+
+    /// Calls `f` with the usage of each thread cache that has mapped any
+    /// chunks. Large mappings aren't owned by a cache, so they're left out.
+    pub fn stats_by_cache(&self, mut f: impl FnMut(usize, &Stats)) {
+        self.for_each_bins(|index, bins| {
+            let mut stats = Stats::default();
+            stats.add_bins(bins);
+            if !stats.is_empty() {
+                f(index, &stats);
+            }
+        });
+    }
+
+    /// Writes the totals followed by a breakdown per thread cache. Never
+    /// allocates, so it's safe to call from the C `malloc_stats`, and only
+    /// reads counters without locking, so it can run in a signal handler as
+    /// long as `out` can.
+    pub fn dump_stats<W: fmt::Write>(&self, out: &mut W, format: StatsFormat) -> fmt::Result {
+        let stats = self.stats();
+        match format {
+            StatsFormat::Table => {
+                writeln!(out, "rsbmalloc stats")?;
+                stats.write_table(out)?;
+                let mut result = Ok(());
+                self.stats_by_cache(|index, cache| {
+                    if result.is_ok() {
+                        result = writeln!(out, "\nthread cache {}", index)
+                            .and_then(|_| cache.write_table(out));
+                    }
+                });
+                result
+            }
+            StatsFormat::Json => {
+                write!(out, "{{\"totals\":")?;
+                stats.write_json(out)?;
+                write!(out, ",\"caches\":[")?;
+                let mut result = Ok(());
+                let mut first = true;
+                self.stats_by_cache(|index, cache| {
+                    if result.is_ok() {
+                        let separator = if first { "" } else { "," };
+                        first = false;
+                        result = write!(out, "{}{{\"index\":{},\"stats\":", separator, index)
+                            .and_then(|_| cache.write_json(out))
+                            .and_then(|_| write!(out, "}}"));
+                    }
+                });
+                result?;
+                writeln!(out, "]}}")
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Synchronization primitives behind the bins and thread caches. Normally
+//! these are `core`'s atomics, `spin`'s mutex and `once_cell`, but with
+//! `--cfg loom` they're loom's, so the models in `loom_test` can explore
+//! every interleaving of the code using them.
+
+#[cfg(not(loom))]
+pub(crate) use core::{hint::spin_loop, sync::atomic};
+#[cfg(all(not(loom), feature = "std"))]
+pub(crate) use once_cell::sync::OnceCell;
+#[cfg(not(loom))]
+pub(crate) use spin::Mutex;
+
+#[cfg(loom)]
+pub(crate) use loom::{hint::spin_loop, sync::atomic};
+
+/// `spin::Mutex`'s interface over loom's mutex
+#[cfg(loom)]
+pub(crate) struct Mutex<T>(loom::sync::Mutex<T>);
+
+#[cfg(loom)]
+impl<T> Mutex<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self(loom::sync::Mutex::new(value))
+    }
+
+    pub(crate) fn lock(&self) -> loom::sync::MutexGuard<'_, T> {
+        self.0.lock().unwrap()
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap()
+    }
+}
+
+/// Just enough of `once_cell`'s `OnceCell` for the thread caches, on loom's
+/// primitives. Losers of the race to initialize wait for the winner.
+#[cfg(loom)]
+pub(crate) struct OnceCell<T> {
+    state: atomic::AtomicUsize,
+    value: loom::cell::UnsafeCell<Option<T>>,
+}
+
+#[cfg(loom)]
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+#[cfg(loom)]
+impl<T> OnceCell<T> {
+    const EMPTY: usize = 0;
+    const RUNNING: usize = 1;
+    const READY: usize = 2;
+
+    pub(crate) fn new() -> Self {
+        Self {
+            state: atomic::AtomicUsize::new(Self::EMPTY),
+            value: loom::cell::UnsafeCell::new(None),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<&T> {
+        if self.state.load(atomic::Ordering::Acquire) != Self::READY {
+            return None;
+        }
+        self.value.with(|value| unsafe { (*value).as_ref() })
+    }
+
+    pub(crate) fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        use atomic::Ordering;
+
+        match self.state.compare_exchange(
+            Self::EMPTY,
+            Self::RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                let value = f();
+                self.value.with_mut(|slot| unsafe { *slot = Some(value) });
+                self.state.store(Self::READY, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != Self::READY {
+                    spin_loop();
+                }
+            }
+        }
+        self.get().unwrap()
+    }
+}
@@ -0,0 +1,200 @@
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{page_allocator::PageSource, side_table::SideTable, RSBMalloc};
+
+pub const MAX_TAGS: usize = 64;
+
+/// A subsystem that allocations can be charged to. `Tag::UNTAGGED` is the
+/// default and isn't counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Tag(u8);
+
+impl Tag {
+    pub const UNTAGGED: Tag = Tag(0);
+
+    /// Panics unless `0 < id < MAX_TAGS`
+    pub const fn new(id: usize) -> Tag {
+        assert!(id > 0 && id < MAX_TAGS, "tag id out of range");
+        Tag(id as u8)
+    }
+
+    pub const fn id(self) -> usize {
+        self.0 as usize
+    }
+}
+
+std::thread_local! {
+    static CURRENT: Cell<Tag> = const { Cell::new(Tag::UNTAGGED) };
+}
+
+/// Runs `f` with allocations on this thread charged to `tag`. Blocks keep
+/// their tag when they're reallocated or freed outside the scope.
+pub fn with_tag<R>(tag: Tag, f: impl FnOnce() -> R) -> R {
+    struct Restore(Tag);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = CURRENT.try_with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(tag)));
+    f()
+}
+
+pub fn current_tag() -> Tag {
+    CURRENT.try_with(Cell::get).unwrap_or(Tag::UNTAGGED)
+}
+
+struct TagCounter {
+    used: AtomicUsize,
+    limit: AtomicUsize,
+}
+
+impl TagCounter {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: TagCounter = TagCounter {
+        used: AtomicUsize::new(0),
+        limit: AtomicUsize::new(usize::MAX),
+    };
+
+    fn try_charge(&self, size: usize) -> bool {
+        let used = self.used.fetch_add(size, Ordering::Relaxed) + size;
+        if used > self.limit.load(Ordering::Relaxed) {
+            self.used.fetch_sub(size, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// Byte counts per tag, plus the tag of every tagged block so frees can be
+/// charged back to it
+pub(crate) struct Tags {
+    counters: [TagCounter; MAX_TAGS],
+    owners: SideTable<Tag>,
+    /// Tagged blocks still allocated. Lets frees skip the table lookup when
+    /// tags aren't in use.
+    outstanding: AtomicUsize,
+}
+
+impl Tags {
+    pub(crate) const fn new() -> Self {
+        Self {
+            counters: [TagCounter::NEW; MAX_TAGS],
+            owners: SideTable::new(),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    /// Charges `size` to the current tag before allocating. `None` means the
+    /// tag is over budget.
+    #[inline]
+    pub(crate) fn reserve(&self, size: usize) -> Option<Tag> {
+        let tag = current_tag();
+        if tag == Tag::UNTAGGED || self.counters[tag.id()].try_charge(size) {
+            Some(tag)
+        } else {
+            None
+        }
+    }
+
+    /// Remembers the tag of a new block, or refunds it if allocating failed
+    #[inline]
+    pub(crate) fn commit(&self, tag: Tag, ptr: *mut u8, size: usize) {
+        if tag == Tag::UNTAGGED {
+            return;
+        }
+        if !ptr.is_null() && self.owners.insert(ptr as usize, tag) {
+            self.outstanding.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters[tag.id()]
+                .used
+                .fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn release(&self, ptr: *mut u8, size: usize) {
+        if self.outstanding.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some(tag) = self.owners.remove(ptr as usize) {
+            self.outstanding.fetch_sub(1, Ordering::Relaxed);
+            self.counters[tag.id()]
+                .used
+                .fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Charges growth to the block's tag before reallocating. Returns false
+    /// if that would put the tag over budget.
+    #[inline]
+    pub(crate) fn reserve_resize(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        if new_size <= old_size || self.outstanding.load(Ordering::Relaxed) == 0 {
+            return true;
+        }
+        match self.owners.get(ptr as usize) {
+            Some(tag) => self.counters[tag.id()].try_charge(new_size - old_size),
+            None => true,
+        }
+    }
+
+    /// Moves the block's tag to its new address, or refunds the growth if
+    /// reallocating failed
+    #[inline]
+    pub(crate) fn commit_resize(
+        &self,
+        old_ptr: *mut u8,
+        new_ptr: *mut u8,
+        old_size: usize,
+        new_size: usize,
+    ) {
+        if self.outstanding.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if new_ptr.is_null() {
+            if new_size > old_size {
+                if let Some(tag) = self.owners.get(old_ptr as usize) {
+                    self.counters[tag.id()]
+                        .used
+                        .fetch_sub(new_size - old_size, Ordering::Relaxed);
+                }
+            }
+            return;
+        }
+        let tag = match self.owners.remove(old_ptr as usize) {
+            Some(tag) => tag,
+            None => return,
+        };
+        let counter = &self.counters[tag.id()];
+        if new_size < old_size {
+            counter
+                .used
+                .fetch_sub(old_size - new_size, Ordering::Relaxed);
+        }
+        if !self.owners.insert(new_ptr as usize, tag) {
+            self.outstanding.fetch_sub(1, Ordering::Relaxed);
+            counter.used.fetch_sub(new_size, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Bytes currently allocated under `tag`
+    pub fn tag_usage(&self, tag: Tag) -> usize {
+        self.tags.counters[tag.id()].used.load(Ordering::Relaxed)
+    }
+
+    /// Caps the bytes allocated under `tag`; allocations that would exceed it
+    /// return null. `None` removes the cap.
+    pub fn set_tag_limit(&self, tag: Tag, limit: Option<usize>) {
+        self.tags.counters[tag.id()]
+            .limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+}
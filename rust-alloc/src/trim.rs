@@ -0,0 +1,241 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr, slice,
+};
+
+use crate::{
+    free_list::{self, FreeList, Lists},
+    limit::Budget,
+    page_allocator::{PageSource, PAGE_ALLOCATOR, PAGE_SIZE},
+    sync::atomic::Ordering,
+    Bin, RSBMalloc, Slice, RSB_CHUNK_SIZE,
+};
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Gives free memory in the bins back to the OS, like glibc's
+    /// `malloc_trim`, and returns how many bytes were released.
+    ///
+    /// Chunks whose slots are all free are unmapped. In the remaining chunks,
+    /// whole pages inside free slots are purged; they stay mapped and fault
+    /// back in when reused. Up to `keep_bytes` of free memory is left alone
+    /// so the next allocations don't all have to fault.
+    ///
+    /// Each bin is locked while it's trimmed. Purged pages aren't tracked, so
+    /// trimming again reports them again.
+    pub fn trim(&self, keep_bytes: usize) -> usize {
+        let mut keep = keep_bytes;
+        let mut released = 0;
+        self.for_each_bins(|_, bins| {
+            for (class, bin) in bins.as_array().iter().enumerate() {
+                released += bin.trim(
+                    &mut keep,
+                    &self.pages,
+                    &self.budget,
+                    TrimMode::Eager,
+                    &|visit| self.class_lists(class, visit),
+                );
+            }
+        });
+        released
+    }
+
+    /// Visits the free list of size class `class` in every cache, any of
+    /// which may hold slots carved by another
+    pub(crate) fn class_lists(&self, class: usize, visit: &mut dyn FnMut(&FreeList)) {
+        self.for_each_bins(|_, bins| visit(&bins.as_array()[class].free_head))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrimMode {
+    /// Leaves chunks mapped and purges free slots with `purge_lazily`
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    Lazy,
+    /// Unmaps free chunks and purges free slots right away
+    Eager,
+}
+
+/// Takes `bytes` out of what's left to keep, returning true if there was
+/// enough left that they should be kept
+fn keep_back(keep: &mut usize, bytes: usize) -> bool {
+    if *keep >= bytes {
+        *keep -= bytes;
+        true
+    } else {
+        false
+    }
+}
+
+impl Bin {
+    /// Releases free memory beyond what's left in `keep`, returning the
+    /// bytes released. `lists` visits every free list the bin's slots may
+    /// have been freed onto, for `free_list::wait_for_pops`.
+    pub(crate) fn trim(
+        &self,
+        keep: &mut usize,
+        pages: &dyn PageSource,
+        budget: &Budget,
+        mode: TrimMode,
+        lists: &Lists,
+    ) -> usize {
+        let slot_size = self.slot_size;
+        let per_chunk = RSB_CHUNK_SIZE / slot_size;
+        let mut chunks = self.chunks.lock();
+        if chunks.len() == 0 {
+            return 0;
+        }
+        let mut tallies = match Tallies::new(chunks.as_slice()) {
+            Some(tallies) => tallies,
+            None => return 0,
+        };
+        // With the cursor parked and the free list detached, every free slot
+        // is ours and nothing new gets carved until we're done. Allocations
+        // in the meantime start a new chunk, which waits on `chunks`.
+        let cursor = self.page.take();
+        let current = Slice::current(cursor);
+        let first = self.free_head.take_all();
+
+        let mut slot = first;
+        while !slot.is_null() {
+            if let Some(tally) = tallies.find(slot) {
+                tally.free += 1;
+            }
+            slot = unsafe { free_list::next(slot) };
+        }
+
+        let mut released = 0;
+        let mut release_current = false;
+        for tally in tallies.as_mut_slice() {
+            let carved = match current {
+                Some((ptr, carved)) if ptr == tally.ptr => carved / slot_size,
+                _ => per_chunk,
+            };
+            if mode == TrimMode::Eager && tally.free == carved && !keep_back(keep, RSB_CHUNK_SIZE) {
+                tally.release = true;
+                release_current |= matches!(current, Some((ptr, _)) if ptr == tally.ptr);
+                released += RSB_CHUNK_SIZE;
+            }
+        }
+
+        // Relink the free list without the released chunks, purging the
+        // pages of each surviving slot past the one holding its link
+        let page_size = *PAGE_SIZE;
+        let link_size = mem::size_of::<usize>();
+        let mut head = ptr::null_mut::<u8>();
+        let mut tail = ptr::null_mut::<u8>();
+        let mut dropped = 0;
+        let mut next = first;
+        while !next.is_null() {
+            let slot = next;
+            next = unsafe { free_list::next(slot) };
+            if tallies.find(slot).map_or(false, |tally| tally.release) {
+                dropped += 1;
+                continue;
+            }
+            let start = (slot as usize + link_size + page_size - 1) & !(page_size - 1);
+            let end = (slot as usize + slot_size) & !(page_size - 1);
+            if end > start && !keep_back(keep, end - start) {
+                let lazily = mode == TrimMode::Lazy;
+                unsafe { pages.purge_pages(start as *mut u8, end - start, lazily) };
+                released += end - start;
+            }
+            unsafe {
+                free_list::set_next(slot, ptr::null_mut());
+                if tail.is_null() {
+                    head = slot;
+                } else {
+                    free_list::set_next(tail, slot);
+                }
+            }
+            tail = slot;
+        }
+        if !head.is_null() {
+            unsafe { self.free_head.push_chain(head, tail) };
+        }
+        self.free_count.fetch_sub(dropped, Ordering::Relaxed);
+
+        // A pop on this or another cache's list may still be reading one of
+        // the released slots
+        if dropped > 0 {
+            free_list::wait_for_pops(lists);
+        }
+        for tally in tallies.as_mut_slice().iter().filter(|tally| tally.release) {
+            unsafe { pages.dealloc_chunk(tally.ptr) };
+            budget.refund(RSB_CHUNK_SIZE);
+        }
+        chunks.retain(|chunk| !tallies.find(chunk.ptr).map_or(false, |tally| tally.release));
+        self.chunk_count.store(chunks.len(), Ordering::Relaxed);
+        if !release_current {
+            self.page.restore(cursor);
+        }
+        released
+    }
+}
+
+struct Tally {
+    ptr: *mut u8,
+    free: usize,
+    release: bool,
+}
+
+/// Free-slot counts per chunk, sorted by address and kept in pages from
+/// `PAGE_ALLOCATOR` so trimming never allocates through the bins
+struct Tallies {
+    ptr: *mut Tally,
+    layout: Layout,
+    len: usize,
+}
+
+impl Tallies {
+    fn new(chunks: &[crate::chunks::Chunk]) -> Option<Self> {
+        let layout = Layout::array::<Tally>(chunks.len()).ok()?;
+        let ptr = unsafe { PAGE_ALLOCATOR.alloc(layout) } as *mut Tally;
+        if ptr.is_null() {
+            return None;
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            unsafe {
+                ptr.add(i).write(Tally {
+                    ptr: chunk.ptr,
+                    free: 0,
+                    release: false,
+                })
+            };
+        }
+        let mut tallies = Self {
+            ptr,
+            layout,
+            len: chunks.len(),
+        };
+        tallies
+            .as_mut_slice()
+            .sort_unstable_by_key(|tally| tally.ptr);
+        Some(tallies)
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [Tally] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    /// The chunk containing `ptr`
+    fn find(&mut self, ptr: *mut u8) -> Option<&mut Tally> {
+        let tallies = self.as_mut_slice();
+        let index = match tallies.binary_search_by_key(&ptr, |tally| tally.ptr) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let tally = &mut tallies[index];
+        if (ptr as usize) < tally.ptr as usize + RSB_CHUNK_SIZE {
+            Some(tally)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Tallies {
+    fn drop(&mut self) {
+        unsafe { PAGE_ALLOCATOR.dealloc(self.ptr as *mut u8, self.layout) };
+    }
+}
@@ -0,0 +1,191 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, slice,
+};
+
+use crate::{
+    chunks::{Chunk, ChunkList},
+    free_list,
+    page_allocator::{PageSource, PAGE_ALLOCATOR},
+    Bin, RSBMalloc, Slice, RSB_CHUNK_SIZE,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockState {
+    Used,
+    Free,
+}
+
+/// One block owned by the allocator, as reported by `RSBMalloc::walk`
+#[derive(Clone, Copy, Debug)]
+pub struct BlockInfo {
+    pub ptr: *mut u8,
+    /// Bytes the block occupies, including slot padding
+    pub size: usize,
+    /// Size class of the bin the block came from, or `None` for large
+    /// allocations
+    pub class: Option<usize>,
+    /// Thread cache owning the block (always 0 without `std`), or `None` for
+    /// large allocations
+    pub cache: Option<usize>,
+    pub state: BlockState,
+}
+
+impl<P: PageSource> RSBMalloc<P> {
+    /// Visits every block carved from a bin chunk, used or free, and every
+    /// large mapping.
+    ///
+    /// Each bin is snapshotted under its locks and `f` runs after they are
+    /// released, so `f` may allocate. Blocks allocated or freed by other
+    /// threads during the walk may be reported in either state.
+    pub fn walk(&self, mut f: impl FnMut(BlockInfo)) {
+        self.for_each_bins(|cache, bins| {
+            for bin in bins.as_array() {
+                bin.walk(cache, &mut f);
+            }
+        });
+        self.walk_large(&mut f);
+    }
+
+    pub(crate) fn walk_large(&self, f: &mut dyn FnMut(BlockInfo)) {
+        let mut snapshot = ChunkList::new();
+        let mut complete = true;
+        self.large.for_each(|ptr, len| {
+            let layout = unsafe { Layout::from_size_align_unchecked(len, 1) };
+            complete &= snapshot.push(ptr, layout);
+        });
+        if !complete {
+            return;
+        }
+        for chunk in snapshot.as_slice() {
+            f(BlockInfo {
+                ptr: chunk.ptr,
+                size: chunk.len,
+                class: None,
+                cache: None,
+                state: BlockState::Used,
+            });
+        }
+    }
+}
+
+impl Bin {
+    /// Reports every carved slot, tagged with the owning cache's index
+    pub(crate) fn walk(&self, cache: usize, f: &mut dyn FnMut(BlockInfo)) {
+        let slot_size = self.slot_size;
+        let per_chunk = RSB_CHUNK_SIZE / slot_size;
+        let (snapshot, current) = {
+            let chunks = self.chunks.lock();
+            if chunks.len() == 0 {
+                return;
+            }
+            let current = Slice::current(self.page.load());
+            let mut snapshot = match Scratch::new(chunks.as_slice(), chunks.len() * per_chunk) {
+                Some(snapshot) => snapshot,
+                None => return,
+            };
+            snapshot
+                .chunks_mut()
+                .sort_unstable_by_key(|chunk| chunk.ptr);
+            let first = self.free_head.take_all();
+            let mut slot = first;
+            while !slot.is_null() {
+                snapshot.mark_free(slot, slot_size, per_chunk);
+                slot = unsafe { free_list::next(slot) };
+            }
+            unsafe { self.free_head.give_back(first) };
+            (snapshot, current)
+        };
+        for (i, chunk) in snapshot.chunks().iter().enumerate() {
+            let carved = match current {
+                Some((ptr, carved)) if ptr == chunk.ptr => carved / slot_size,
+                _ => per_chunk,
+            };
+            for slot in 0..carved {
+                f(BlockInfo {
+                    ptr: unsafe { chunk.ptr.add(slot * slot_size) },
+                    size: slot_size,
+                    class: Some(self.class_size),
+                    cache: Some(cache),
+                    state: if snapshot.is_free(i * per_chunk + slot) {
+                        BlockState::Free
+                    } else {
+                        BlockState::Used
+                    },
+                });
+            }
+        }
+    }
+}
+
+/// A copy of a chunk list plus a bitmap of free slots, kept in pages from
+/// `PAGE_ALLOCATOR` so taking it never allocates through the bins
+struct Scratch {
+    ptr: *mut u8,
+    layout: Layout,
+    len: usize,
+    bits: usize,
+}
+
+impl Scratch {
+    fn new(chunks: &[Chunk], bits: usize) -> Option<Self> {
+        let bitmap_offset = mem::size_of_val(chunks);
+        let size = bitmap_offset + (bits + 7) / 8;
+        let layout = Layout::from_size_align(size.max(1), mem::align_of::<Chunk>()).ok()?;
+        let ptr = unsafe { PAGE_ALLOCATOR.alloc(layout) };
+        if ptr.is_null() {
+            return None;
+        }
+        unsafe {
+            (ptr as *mut Chunk).copy_from_nonoverlapping(chunks.as_ptr(), chunks.len());
+        }
+        Some(Self {
+            ptr,
+            layout,
+            len: chunks.len(),
+            bits,
+        })
+    }
+
+    fn chunks(&self) -> &[Chunk] {
+        unsafe { slice::from_raw_parts(self.ptr as *const Chunk, self.len) }
+    }
+
+    fn chunks_mut(&mut self) -> &mut [Chunk] {
+        unsafe { slice::from_raw_parts_mut(self.ptr as *mut Chunk, self.len) }
+    }
+
+    /// Fresh pages are zeroed, so every slot starts out used
+    fn bitmap(&self) -> *mut u8 {
+        unsafe { self.ptr.add(self.len * mem::size_of::<Chunk>()) }
+    }
+
+    /// Needs the chunks sorted by address
+    fn mark_free(&mut self, slot: *mut u8, slot_size: usize, per_chunk: usize) {
+        let chunks = self.chunks();
+        let index = match chunks.binary_search_by_key(&slot, |chunk| chunk.ptr) {
+            Ok(index) => index,
+            Err(0) => return,
+            Err(index) => index - 1,
+        };
+        let chunk = chunks[index];
+        let offset = slot as usize - chunk.ptr as usize;
+        if offset >= chunk.len {
+            return;
+        }
+        let bit = index * per_chunk + offset / slot_size;
+        if bit < self.bits {
+            unsafe { *self.bitmap().add(bit / 8) |= 1 << (bit % 8) };
+        }
+    }
+
+    fn is_free(&self, bit: usize) -> bool {
+        bit < self.bits && unsafe { *self.bitmap().add(bit / 8) } & (1 << (bit % 8)) != 0
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        unsafe { PAGE_ALLOCATOR.dealloc(self.ptr, self.layout) };
+    }
+}
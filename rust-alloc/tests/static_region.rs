@@ -0,0 +1,86 @@
+//! rsbmalloc as the global allocator, with every page coming from a region
+//! handed to `PAGE_ALLOCATOR.set_region`. Run with
+//! `cargo test --features static_region --test static_region`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::addr_of_mut,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Once,
+    },
+    thread,
+};
+
+use rsbmalloc::{page_allocator::PAGE_ALLOCATOR, RSBMalloc};
+
+const REGION_SIZE: usize = 64 << 20;
+
+static mut REGION: [u8; REGION_SIZE] = [0; REGION_SIZE];
+static SET_REGION: Once = Once::new();
+static REGION_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the region on the first allocation, since the test harness allocates
+/// before any test gets to run
+struct Global(RSBMalloc);
+
+unsafe impl GlobalAlloc for Global {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        SET_REGION.call_once(|| {
+            let region = &mut *addr_of_mut!(REGION);
+            REGION_START.store(region.as_ptr() as usize, Ordering::Relaxed);
+            if !PAGE_ALLOCATOR.set_region(region) {
+                std::process::abort();
+            }
+        });
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Global = Global(RSBMalloc::new());
+
+fn in_region(ptr: *const u8) -> bool {
+    let start = REGION_START.load(Ordering::Relaxed);
+    (start..start + REGION_SIZE).contains(&(ptr as usize))
+}
+
+#[test]
+fn global_allocator() {
+    assert!(!PAGE_ALLOCATOR.set_region(Box::leak(Box::new([0; 0x10000]))));
+
+    let small = Box::new(7_u64);
+    assert!(in_region(&*small as *const u64 as *const u8));
+
+    let mut large = vec![1_u8; 300_000];
+    assert!(in_region(large.as_ptr()));
+    large.resize(600_000, 2);
+    assert!(in_region(large.as_ptr()));
+    assert_eq!((large[299_999], large[599_999]), (1, 2));
+
+    let strings: Vec<String> = (0..10_000).map(|i| i.to_string()).collect();
+    assert!(strings.iter().all(|s| in_region(s.as_ptr())));
+    assert_eq!(strings[9_999], "9999");
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let blocks: Vec<Vec<usize>> = (0..1000).map(|j| vec![i + j; j % 64 + 1]).collect();
+                blocks
+                    .iter()
+                    .all(|block| in_region(block.as_ptr() as *const u8))
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert!(thread.join().unwrap());
+    }
+}